@@ -0,0 +1,86 @@
+//! Functions provided by the language rather than defined in the program.
+//!
+//! A call to a builtin's name compiles to a single BUILTIN instruction, unless
+//! the program binds that name itself, in which case its own binding wins.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Builtin {
+    // converts a number to an integer, rounding toward zero
+    Int,
+    // converts a number to a float
+    Float,
+    // converts any value to the string it prints as
+    Str,
+    // length of a string in characters, or of a list
+    Len,
+    // substr(s, start, count): `count` characters of `s` from `start`
+    Substr,
+    // compare(a, b): -1, 0 or 1 as `a` sorts before, with or after `b`
+    Compare,
+    // push(xs, x): a new list with `x` after the elements of `xs`
+    Push,
+    // slice(xs, start, end): a new list of the elements from `start` up to,
+    // but not including, `end`
+    Slice,
+}
+
+impl Builtin {
+    /// Every builtin, in the order of their BUILTIN operands.
+    pub const ALL: &[Builtin] = &[
+        Builtin::Int,
+        Builtin::Float,
+        Builtin::Str,
+        Builtin::Len,
+        Builtin::Substr,
+        Builtin::Compare,
+        Builtin::Push,
+        Builtin::Slice,
+    ];
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.iter().copied().find(|builtin| builtin.name() == name)
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Builtin::Int => "int",
+            Builtin::Float => "float",
+            Builtin::Str => "str",
+            Builtin::Len => "len",
+            Builtin::Substr => "substr",
+            Builtin::Compare => "compare",
+            Builtin::Push => "push",
+            Builtin::Slice => "slice",
+        }
+    }
+
+    pub fn arity(self) -> usize {
+        match self {
+            Builtin::Int | Builtin::Float | Builtin::Str | Builtin::Len => 1,
+            Builtin::Compare | Builtin::Push => 2,
+            Builtin::Substr | Builtin::Slice => 3,
+        }
+    }
+
+    /// What the arguments must be, for type mismatch messages.
+    pub fn expects(self) -> &'static str {
+        match self {
+            Builtin::Int | Builtin::Float => "a number",
+            Builtin::Str => "any value",
+            Builtin::Len => "a string or a list",
+            Builtin::Substr => "a string and two integers",
+            Builtin::Compare => "two strings, two lists or two numbers",
+            Builtin::Push => "a list and a value",
+            Builtin::Slice => "a list and two integers",
+        }
+    }
+
+    /// The operand of the BUILTIN instruction that calls this builtin.
+    pub fn index(self) -> i32 {
+        Self::ALL.iter().position(|&builtin| builtin == self).unwrap() as i32
+    }
+
+    pub fn from_index(index: i32) -> Option<Self> {
+        Self::ALL.get(usize::try_from(index).ok()?).copied()
+    }
+}
@@ -1,66 +1,411 @@
 use crate::{
-    parser::{BinaryOp, Expr, UnaryOp},
-    vm::Instruction,
+    error::{CompileErrorKind, Error},
+    parser::{BinaryOp, Expr, ExprKind, Param, Pragma, UnaryOp},
+    lexer::Span,
+    resolver::{Binding, BindingId, BindingKind, Resolved},
+    value::Value,
+    vm::{FunctionInfo, Instruction, Program, MAX_LOCALS},
 };
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap};
 
-pub struct Compiler {
-    var_map: HashMap<String, usize>,
+// slot bookkeeping for one frame: the top level or a single function body
+#[derive(Default)]
+struct Locals {
+    slots: HashMap<BindingId, usize>,
     next_register: usize,
+    // registers whose binding's scope has closed; handed out again before the
+    // locals area grows, so sibling scopes share slots
+    free_registers: Vec<usize>,
+}
+
+pub struct Compiler {
+    locals: Locals,
+    // index into `Program::functions` for every function binding in scope
+    functions: HashMap<BindingId, usize>,
+    // outer variables each function needs, in the order its frame stores them
+    captures: HashMap<BindingId, Vec<BindingId>>,
 }
+impl Default for Compiler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Compiler {
     pub fn new() -> Self {
         Self {
-            var_map: HashMap::new(),
-            next_register: 0,
+            locals: Locals::default(),
+            functions: HashMap::new(),
+            captures: HashMap::new(),
+        }
+    }
+    // registers here are slots in the current frame of the VM's locals area,
+    // which grows as needed
+    fn allocate_register(&mut self, span: Span) -> Result<usize, Error> {
+        if let Some(reg) = self.locals.free_registers.pop() {
+            return Ok(reg);
+        }
+        let reg = self.locals.next_register;
+        if reg >= MAX_LOCALS {
+            return Err(Error::compile(
+                CompileErrorKind::TooManyLocals,
+                format!("Too many variables: the limit is {}", MAX_LOCALS),
+                span,
+            ));
+        }
+        self.locals.next_register += 1;
+        Ok(reg)
+    }
+
+    // a binding is dead once the body of its define has been compiled: nothing
+    // outside that body can name it
+    fn free_register(&mut self, reg: usize) {
+        self.locals.free_registers.push(reg);
+    }
+
+    fn captures_of(&self, function: BindingId) -> &[BindingId] {
+        self.captures.get(&function).map_or(&[], Vec::as_slice)
+    }
+
+    // pushes the current value of everything `function` captures; a call
+    // passes them after the arguments and a closure keeps them
+    fn push_captures(
+        &mut self,
+        function: BindingId,
+        span: Span,
+        out: &mut Program,
+    ) -> Result<(), Error> {
+        for &captured in self.captures_of(function) {
+            let Some(&reg) = self.locals.slots.get(&captured) else {
+                return Err(unresolved("<captured variable>", span));
+            };
+            out.emit(Instruction::GET, span);
+            out.emit_operand(reg as i32);
+        }
+        Ok(())
+    }
+
+    fn compile_function_body(
+        &mut self,
+        function: BindingId,
+        params: &[Param],
+        func_body: &Expr,
+        out: &mut Program,
+    ) -> Result<(), Error> {
+        for param in params {
+            let Some(binding) = param.binding else {
+                return Err(unresolved(&param.name, param.span));
+            };
+            let reg = self.allocate_register(param.span)?;
+            self.locals.slots.insert(binding, reg);
+        }
+        // captured values follow the arguments and are read like any local
+        for captured in self.captures_of(function).to_vec() {
+            let reg = self.allocate_register(func_body.span)?;
+            self.locals.slots.insert(captured, reg);
+        }
+        self.compile_node(func_body, out, true)
+    }
+
+    // `and`/`or` with the same JMZ/JMP patching as `if`: the right side only
+    // runs when the left one has not decided the result already. Whatever
+    // runs last goes through `compile_truth` so the result is always a bool
+    fn compile_logical(
+        &mut self,
+        left: &Expr,
+        op: &BinaryOp,
+        right: &Expr,
+        span: Span,
+        out: &mut Program,
+    ) -> Result<(), Error> {
+        self.compile_expression(left, out)?;
+        let jz_pos = out.len();
+        out.emit(Instruction::JMZ, span);
+        out.emit_operand(0);
+
+        let is_and = matches!(op, BinaryOp::And);
+        if is_and {
+            self.compile_truth(right, true, span, out)?;
+        } else {
+            self.emit_bool(true, span, out);
+        }
+        let jmp_pos = out.len();
+        out.emit(Instruction::JMP, span);
+        out.emit_operand(0);
+
+        // the left side was false
+        out.code[jz_pos + 1] = out.len() as i32;
+        if is_and {
+            self.emit_bool(false, span, out);
+        } else {
+            self.compile_truth(right, true, span, out)?;
+        }
+        out.code[jmp_pos + 1] = out.len() as i32;
+        Ok(())
+    }
+
+    // pushes `when_true` if `expr` is true and its negation otherwise. JMZ
+    // decides, so this agrees with `if` on what counts as false: any zero and
+    // `false`, while strings, lists and functions are all true
+    fn compile_truth(
+        &mut self,
+        expr: &Expr,
+        when_true: bool,
+        span: Span,
+        out: &mut Program,
+    ) -> Result<(), Error> {
+        self.compile_expression(expr, out)?;
+        let jz_pos = out.len();
+        out.emit(Instruction::JMZ, span);
+        out.emit_operand(0);
+        self.emit_bool(when_true, span, out);
+        let jmp_pos = out.len();
+        out.emit(Instruction::JMP, span);
+        out.emit_operand(0);
+        out.code[jz_pos + 1] = out.len() as i32;
+        self.emit_bool(!when_true, span, out);
+        out.code[jmp_pos + 1] = out.len() as i32;
+        Ok(())
+    }
+
+    fn emit_bool(&mut self, value: bool, span: Span, out: &mut Program) {
+        let index = out.add_constant(Value::Bool(value));
+        out.emit(Instruction::CONST, span);
+        out.emit_operand(index);
+    }
+
+    // `set (name value)`, or `set (name[index] value)` for one list element
+    fn compile_assignment(
+        &mut self,
+        name: &str,
+        binding: Option<BindingId>,
+        index: Option<&Expr>,
+        value: &Expr,
+        span: Span,
+        out: &mut Program,
+    ) -> Result<(), Error> {
+        let Some(&reg_id) = binding.as_ref().and_then(|id| self.locals.slots.get(id)) else {
+            return Err(unresolved(name, span));
+        };
+        if let Some(index) = index {
+            self.compile_expression(index, out)?;
+        }
+        self.compile_expression(value, out)?;
+        if index.is_some() {
+            // the list is read last, after anything the index or the value
+            // assigned
+            out.emit(Instruction::GET, span);
+            out.emit_operand(reg_id as i32);
+            out.emit(Instruction::STORE, span);
+        }
+        out.emit(Instruction::SET, span);
+        out.emit_operand(reg_id as i32);
+        // the assignment's own value
+        out.emit(Instruction::GET, span);
+        out.emit_operand(reg_id as i32);
+        Ok(())
+    }
+
+    // `fn (a ... fn (b ... rest))`: every function in the chain is callable
+    // from all of their bodies, which is what makes mutual recursion work
+    fn compile_function_group(
+        &mut self,
+        expr: &Expr,
+        out: &mut Program,
+        tail: bool,
+    ) -> Result<(), Error> {
+        let mut group = Vec::new();
+        let mut rest = expr;
+        while let ExprKind::Function {
+            name,
+            binding,
+            params,
+            func_body,
+            body,
+            ..
+        } = &rest.kind
+        {
+            let Some(binding) = *binding else {
+                return Err(unresolved(name, rest.span));
+            };
+            let index = out.functions.len();
+            out.functions.push(FunctionInfo {
+                name: name.clone(),
+                entry: 0,
+                arity: params.len(),
+                captures: self.captures_of(binding).len(),
+                num_locals: 0,
+            });
+            self.functions.insert(binding, index);
+            group.push((binding, index, params, func_body));
+            rest = body;
+        }
+
+        // the bodies are laid out inline; straight-line code jumps over them
+        let skip_pos = out.len();
+        out.emit(Instruction::JMP, expr.span);
+        out.emit_operand(0);
+
+        for &(binding, index, params, func_body) in &group {
+            out.functions[index].entry = out.len();
+
+            // a function gets a fresh frame, so its slots start from 0 and
+            // the arguments land in the first `arity` of them
+            let outer = std::mem::take(&mut self.locals);
+            let compiled = self.compile_function_body(binding, params, func_body, out);
+            let inner = std::mem::replace(&mut self.locals, outer);
+            compiled?;
+            out.functions[index].num_locals = inner.next_register;
+
+            out.emit(Instruction::RET, func_body.span);
+        }
+        let after = out.len();
+        out.code[skip_pos + 1] = after as i32;
+
+        self.compile_node(rest, out, tail)?;
+        for (binding, ..) in group {
+            self.functions.remove(&binding);
         }
+        Ok(())
     }
-    fn allocate_register(&mut self) -> usize {
-        let reg = self.next_register;
-        self.next_register += 1;
-        reg
+
+    pub fn compile_expression(&mut self, expr: &Expr, out: &mut Program) -> Result<(), Error> {
+        self.compile_node(expr, out, false)
     }
 
-    pub fn compile_expression(&mut self, expr: &Expr, out: &mut Vec<i32>) {
-        match expr {
-            Expr::Number(n) => {
-                out.push(Instruction::PSH as i32);
-                out.push(*n);
+    // `tail` is set when the value of `expr` is what the enclosing function
+    // returns, so a call there can reuse the current frame
+    fn compile_node(&mut self, expr: &Expr, out: &mut Program, tail: bool) -> Result<(), Error> {
+        let span = expr.span;
+        match &expr.kind {
+            ExprKind::Number(n) => {
+                out.emit(Instruction::PSH, span);
+                out.emit_operand(*n);
             }
-            Expr::Variable(name) => {
-                let reg_id = self
-                    .var_map
-                    .get(name)
-                    .expect(&format!("Undefined Variable : {}", name));
-                out.push(Instruction::GET as i32);
-                out.push(*reg_id as i32);
+            ExprKind::Float(x) => {
+                let index = out.add_constant(Value::Float(*x));
+                out.emit(Instruction::CONST, span);
+                out.emit_operand(index);
             }
-            Expr::Define { name, value, body } => {
-                self.compile_expression(value, out);
-                
-                // Check if variable already exists (reassignment in loops)
-                let (reg_id, is_new) = if let Some(&existing_reg) = self.var_map.get(name) {
-                    (existing_reg, false)  // Reuse existing register
-                } else {
-                    let new_reg = self.allocate_register();
-                    (new_reg, true)  // Allocate new register
-                };
-                
-                if is_new {
-                    self.var_map.insert(name.clone(), reg_id);
+            ExprKind::Bool(b) => self.emit_bool(*b, span, out),
+            ExprKind::Str(text) => {
+                let index = out.add_constant(Value::Str(text.as_str().into()));
+                out.emit(Instruction::CONST, span);
+                out.emit_operand(index);
+            }
+            ExprKind::List(elements) => {
+                for element in elements {
+                    self.compile_expression(element, out)?;
                 }
+                out.emit(Instruction::LIST, span);
+                out.emit_operand(elements.len() as i32);
+            }
+            ExprKind::Index { target, index } => {
+                self.compile_expression(target, out)?;
+                self.compile_expression(index, out)?;
+                out.emit(Instruction::INDEX, span);
+            }
+            ExprKind::Variable {
+                binding: Some(id),
+                ..
+            } if self.functions.contains_key(id) => {
+                // a function used as a value becomes a closure over the
+                // current values of its captures
+                self.push_captures(*id, span, out)?;
+                out.emit(Instruction::CLOSURE, span);
+                out.emit_operand(self.functions[id] as i32);
+            }
+            ExprKind::Variable { name, binding } => {
+                let Some(&reg_id) = binding.and_then(|id| self.locals.slots.get(&id)) else {
+                    return Err(unresolved(name, span));
+                };
+                out.emit(Instruction::GET, span);
+                out.emit_operand(reg_id as i32);
+            }
+            ExprKind::Define {
+                name,
+                binding,
+                value,
+                body,
+                ..
+            } => {
+                let Some(binding) = *binding else {
+                    return Err(unresolved(name, span));
+                };
+                self.compile_expression(value, out)?;
+
+                let reg_id = self.allocate_register(span)?;
+                self.locals.slots.insert(binding, reg_id);
 
-                out.push(Instruction::SET as i32);
-                out.push(reg_id as i32);
+                out.emit(Instruction::SET, span);
+                out.emit_operand(reg_id as i32);
 
-                self.compile_expression(body, out);
-                if is_new {
-                    self.var_map.remove(name);
+                self.compile_node(body, out, tail)?;
+                self.locals.slots.remove(&binding);
+                self.free_register(reg_id);
+            }
+            ExprKind::Block(exprs) => {
+                // the parser never builds an empty block, but the AST is public
+                let Some((last, rest)) = exprs.split_last() else {
+                    return Err(Error::compile(
+                        CompileErrorKind::InvalidSyntax,
+                        "Cannot compile an empty block",
+                        span,
+                    ));
+                };
+                // only the last value is kept
+                for expr in rest {
+                    self.compile_expression(expr, out)?;
+                    out.emit(Instruction::POP, span);
+                }
+                self.compile_node(last, out, tail)?;
+            }
+            ExprKind::Set {
+                name,
+                binding,
+                index,
+                value,
+                ..
+            } => self.compile_assignment(name, *binding, index.as_deref(), value, span, out)?,
+            ExprKind::Function { .. } => self.compile_function_group(expr, out, tail)?,
+            ExprKind::Call {
+                callee,
+                args,
+                builtin,
+            } => {
+                for arg in args {
+                    self.compile_expression(arg, out)?;
+                }
+                let known = match &callee.kind {
+                    ExprKind::Variable {
+                        binding: Some(id), ..
+                    } => self.functions.get(id).map(|&index| (*id, index)),
+                    _ => None,
+                };
+                if let Some(builtin) = builtin {
+                    out.emit(Instruction::BUILTIN, span);
+                    out.emit_operand(builtin.index());
+                } else if let Some((function, index)) = known {
+                    self.push_captures(function, span, out)?;
+                    let instr = if tail { Instruction::TAILCALL } else { Instruction::CALL };
+                    out.emit(instr, span);
+                    out.emit_operand(index as i32);
+                } else {
+                    // anything else has to evaluate to a closure
+                    self.compile_expression(callee, out)?;
+                    let instr = if tail { Instruction::TAILCALLV } else { Instruction::CALLV };
+                    out.emit(instr, span);
+                    out.emit_operand(args.len() as i32);
                 }
             }
-            Expr::Binary { left, op, right } => {
-                self.compile_expression(left, out);
-                self.compile_expression(right, out);
+            ExprKind::Binary {
+                left,
+                op: op @ (BinaryOp::And | BinaryOp::Or),
+                right,
+            } => self.compile_logical(left, op, right, span, out)?,
+            ExprKind::Binary { left, op, right } => {
+                self.compile_expression(left, out)?;
+                self.compile_expression(right, out)?;
 
                 let instr = match op {
                     BinaryOp::Add => Instruction::ADD,
@@ -76,23 +421,34 @@ impl Compiler {
                     BinaryOp::Greater => Instruction::GTR,
                     BinaryOp::LessEq => Instruction::LEQ,
                     BinaryOp::GreaterEq => Instruction::GEQ,
+                    BinaryOp::BitAnd => Instruction::BAND,
+                    BinaryOp::BitOr => Instruction::BOR,
+                    BinaryOp::BitXor => Instruction::BXOR,
+                    BinaryOp::Shl => Instruction::SHL,
+                    BinaryOp::Shr => Instruction::SHR,
+                    BinaryOp::And | BinaryOp::Or => unreachable!("lowered to jumps above"),
                 };
 
-                out.push(instr as i32);
+                out.emit(instr, span);
             }
-            Expr::Unary { op, expr } => match op {
+            ExprKind::Unary { op, expr } => match op {
                 UnaryOp::Neg => {
-                    out.push(Instruction::PSH as i32);
-                    out.push(0);
-                    self.compile_expression(expr, out);
-                    out.push(Instruction::SUB as i32);
+                    out.emit(Instruction::PSH, span);
+                    out.emit_operand(0);
+                    self.compile_expression(expr, out)?;
+                    out.emit(Instruction::SUB, span);
+                }
+                UnaryOp::Not => self.compile_truth(expr, false, span, out)?,
+                UnaryOp::BitNot => {
+                    self.compile_expression(expr, out)?;
+                    out.emit(Instruction::BNOT, span);
                 }
             },
-            Expr::If { condition, then_branch, else_branch } => {
-                self.compile_expression(condition, out);
+            ExprKind::If { condition, then_branch, else_branch } => {
+                self.compile_expression(condition, out)?;
                 let jz_pos = out.len(); 
-                out.push(Instruction::JMZ as i32);
-                out.push(0);
+                out.emit(Instruction::JMZ, span);
+                out.emit_operand(0);
                 // magic function -> writes byte code of 'if' expression 
                 //  and a placeholder JumpIfZero (Jump if return false) with target as 0 
                 // then writes then expression with a JMP to end of 'if' block
@@ -103,58 +459,280 @@ impl Compiler {
                 // writing this so that I do not forget in future and also 
                 // because this logic tickles my brain
 
-                self.compile_expression(then_branch, out);
+                self.compile_node(then_branch, out, tail)?;
 
                 let jmp_pos = out.len();
-                out.push(Instruction::JMP as i32);
-                out.push(0);
+                out.emit(Instruction::JMP, span);
+                out.emit_operand(0);
 
                 let else_addr = out.len();
-                out[jz_pos +1] = else_addr as i32;
+                out.code[jz_pos + 1] = else_addr as i32;
 
-                self.compile_expression(else_branch, out);
+                self.compile_node(else_branch, out, tail)?;
                 let end_addr = out.len();
-                out[jmp_pos +1] = end_addr as i32;
+                out.code[jmp_pos + 1] = end_addr as i32;
                 
             }
-            Expr::While { condition, body } => {
+            ExprKind::While { condition, body } => {
                 // push initial dummy value (will be replaced by body result)
-                out.push(Instruction::PSH as i32);
-                out.push(0);
+                out.emit(Instruction::PSH, span);
+                out.emit_operand(0);
                 
                 let loop_start = out.len();
                 //if loop succeed we send the command flow to be back here; 
-                self.compile_expression(condition, out);
+                self.compile_expression(condition, out)?;
 
                 let jmz_pos = out.len();
                 //if condition fails we send the command flow to end of while loop ;
-                out.push(Instruction::JMZ as i32);
-                out.push(0);   
+                out.emit(Instruction::JMZ, span);
+                out.emit_operand(0);   
 
                 // This also tickles my brain -> we push a dummy value on stack first. 
                 // remove that value before putting the vlue that is being calculated in this 
                 // iteration. so in the first iter dummy is removed, and then subsequesntly 
                 // all the middle ones are removed, only keeping the final calc at the stack; 
-                out.push(Instruction::POP as i32);
+                out.emit(Instruction::POP, span);
 
-                self.compile_expression(body, out);
+                self.compile_expression(body, out)?;
 
-                out.push(Instruction::JMP as i32);
-                out.push(loop_start as i32);
+                out.emit(Instruction::JMP, span);
+                out.emit_operand(loop_start as i32);
 
                 let loop_end = out.len();
-                out[jmz_pos + 1] = loop_end as i32;
+                out.code[jmz_pos + 1] = loop_end as i32;
+
+            }
+            ExprKind::Pragma { pragma, body } => {
+                match pragma {
+                    Pragma::Overflow(mode) => out.overflow = Some(*mode),
+                    Pragma::Division(mode) => out.division = Some(*mode),
+                }
+                self.compile_node(body, out, tail)?;
+            }
+            ExprKind::Error => {
+                return Err(Error::compile(
+                    CompileErrorKind::InvalidSyntax,
+                    "Cannot compile an expression that failed to parse",
+                    span,
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+// only reachable when compiling an AST that did not go through the resolver
+fn unresolved(name: &str, span: Span) -> Error {
+    Error::compile(
+        CompileErrorKind::UndefinedVariable,
+        format!("Undefined Variable : {}", name),
+        span,
+    )
+}
+
+// every binding `expr` names, including inside nested function bodies
+fn collect_references(expr: &Expr, found: &mut BTreeSet<BindingId>) {
+    match &expr.kind {
+        ExprKind::Number(_)
+        | ExprKind::Float(_)
+        | ExprKind::Bool(_)
+        | ExprKind::Str(_)
+        | ExprKind::Error => {}
+        ExprKind::Variable { binding, .. } => found.extend(*binding),
+        ExprKind::Define { value, body, .. } => {
+            collect_references(value, found);
+            collect_references(body, found);
+        }
+        ExprKind::Set {
+            binding,
+            index,
+            value,
+            ..
+        } => {
+            found.extend(*binding);
+            if let Some(index) = index {
+                collect_references(index, found);
+            }
+            collect_references(value, found);
+        }
+        ExprKind::Function {
+            func_body, body, ..
+        } => {
+            collect_references(func_body, found);
+            collect_references(body, found);
+        }
+        ExprKind::Call { callee, args, .. } => {
+            collect_references(callee, found);
+            for arg in args {
+                collect_references(arg, found);
+            }
+        }
+        ExprKind::List(elements) | ExprKind::Block(elements) => {
+            for element in elements {
+                collect_references(element, found);
+            }
+        }
+        ExprKind::Index { target, index } => {
+            collect_references(target, found);
+            collect_references(index, found);
+        }
+        ExprKind::Unary { expr, .. } | ExprKind::Pragma { body: expr, .. } => {
+            collect_references(expr, found)
+        }
+        ExprKind::Binary { left, right, .. } => {
+            collect_references(left, found);
+            collect_references(right, found);
+        }
+        ExprKind::If {
+            condition,
+            then_branch,
+            else_branch,
+        } => {
+            collect_references(condition, found);
+            collect_references(then_branch, found);
+            collect_references(else_branch, found);
+        }
+        ExprKind::While { condition, body } => {
+            collect_references(condition, found);
+            collect_references(body, found);
+        }
+    }
+}
+
+// (function, names used in its body) for every function in `expr`
+fn collect_functions(expr: &Expr, functions: &mut Vec<(BindingId, BTreeSet<BindingId>)>) {
+    if let ExprKind::Function {
+        binding: Some(id),
+        func_body,
+        ..
+    } = &expr.kind
+    {
+        let mut found = BTreeSet::new();
+        collect_references(func_body, &mut found);
+        functions.push((*id, found));
+    }
+    match &expr.kind {
+        ExprKind::Number(_)
+        | ExprKind::Float(_)
+        | ExprKind::Bool(_)
+        | ExprKind::Str(_)
+        | ExprKind::Variable { .. }
+        | ExprKind::Error => {}
+        ExprKind::Define { value, body, .. } => {
+            collect_functions(value, functions);
+            collect_functions(body, functions);
+        }
+        ExprKind::Set { index, value, .. } => {
+            if let Some(index) = index {
+                collect_functions(index, functions);
+            }
+            collect_functions(value, functions);
+        }
+        ExprKind::Function {
+            func_body, body, ..
+        } => {
+            collect_functions(func_body, functions);
+            collect_functions(body, functions);
+        }
+        ExprKind::Call { callee, args, .. } => {
+            collect_functions(callee, functions);
+            for arg in args {
+                collect_functions(arg, functions);
+            }
+        }
+        ExprKind::List(elements) | ExprKind::Block(elements) => {
+            for element in elements {
+                collect_functions(element, functions);
+            }
+        }
+        ExprKind::Index { target, index } => {
+            collect_functions(target, functions);
+            collect_functions(index, functions);
+        }
+        ExprKind::Unary { expr, .. } | ExprKind::Pragma { body: expr, .. } => {
+            collect_functions(expr, functions)
+        }
+        ExprKind::Binary { left, right, .. } => {
+            collect_functions(left, functions);
+            collect_functions(right, functions);
+        }
+        ExprKind::If {
+            condition,
+            then_branch,
+            else_branch,
+        } => {
+            collect_functions(condition, functions);
+            collect_functions(then_branch, functions);
+            collect_functions(else_branch, functions);
+        }
+        ExprKind::While { condition, body } => {
+            collect_functions(condition, functions);
+            collect_functions(body, functions);
+        }
+    }
+}
 
+/// Works out which variables of enclosing scopes each function captures.
+///
+/// A function captures every variable defined outside it that its body
+/// names, plus whatever the functions it calls or builds closures of capture
+/// from outside it, since it has to hand those values on.
+pub fn analyze_captures(resolved: &Resolved) -> HashMap<BindingId, Vec<BindingId>> {
+    let bindings: &[Binding] = &resolved.bindings;
+    let mut functions = Vec::new();
+    collect_functions(&resolved.expr, &mut functions);
+
+    // everything inside a function's body is at least one level deeper
+    // than the function itself, so "defined outside" is a depth check
+    let outside = |function: BindingId, id: BindingId| {
+        bindings[id.0].depth <= bindings[function.0].depth
+    };
+    let mut captures: HashMap<BindingId, BTreeSet<BindingId>> = functions
+        .iter()
+        .map(|(function, found)| {
+            let direct = found
+                .iter()
+                .copied()
+                .filter(|&id| bindings[id.0].kind == BindingKind::Variable && outside(*function, id))
+                .collect();
+            (*function, direct)
+        })
+        .collect();
+
+    // mutually recursive functions feed each other, so repeat until stable
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for (function, found) in &functions {
+            let inherited: Vec<BindingId> = found
+                .iter()
+                .filter(|id| id != &function)
+                .filter_map(|id| captures.get(id))
+                .flatten()
+                .copied()
+                .filter(|&id| outside(*function, id))
+                .collect();
+            let own = captures.get_mut(function).unwrap();
+            for id in inherited {
+                changed |= own.insert(id);
             }
         }
     }
+
+    captures
+        .into_iter()
+        .map(|(function, set)| (function, set.into_iter().collect()))
+        .collect()
 }
 
-// out -> contains the bytecode for vm (form of Vec<i32>)
-pub fn compile(expr: &Expr) -> Vec<i32> {
+// out -> contains the bytecode for vm (form of Vec<i32>) plus the span table
+pub fn compile(resolved: &Resolved) -> Result<Program, Error> {
     let mut compiler = Compiler::new();
-    let mut program = Vec::new();
-    compiler.compile_expression(expr, &mut program);
-    program.push(Instruction::HLT as i32);
-    program
+    compiler.captures = analyze_captures(resolved);
+    let mut program = Program::new();
+    compiler.compile_expression(&resolved.expr, &mut program)?;
+    program.emit(Instruction::HLT, resolved.expr.span);
+    // the high-water mark: every slot ever handed out, reused or not
+    program.num_locals = compiler.locals.next_register;
+    Ok(program)
 }
@@ -0,0 +1,231 @@
+use std::fmt::Write;
+
+use crate::error::{CompileErrorKind, Error, LexErrorKind, ParseErrorKind, RuntimeErrorKind};
+use crate::lexer::Span;
+use crate::vm::MAX_LOCALS;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Label {
+    pub span: Span,
+    pub message: String,
+    // primary labels are underlined with '^', secondary ones with '-'
+    pub primary: bool,
+}
+
+/// A message about the source, rendered rustc-style with the offending lines
+/// underlined.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub message: String,
+    pub labels: Vec<Label>,
+    pub notes: Vec<String>,
+}
+
+impl Diagnostic {
+    pub fn error(message: impl Into<String>) -> Self {
+        Self {
+            severity: Severity::Error,
+            message: message.into(),
+            labels: Vec::new(),
+            notes: Vec::new(),
+        }
+    }
+
+    pub fn warning(message: impl Into<String>) -> Self {
+        Self {
+            severity: Severity::Warning,
+            ..Self::error(message)
+        }
+    }
+
+    pub fn with_label(mut self, span: Span, message: impl Into<String>) -> Self {
+        self.labels.push(Label {
+            span,
+            message: message.into(),
+            primary: true,
+        });
+        self
+    }
+
+    pub fn with_secondary(mut self, span: Span, message: impl Into<String>) -> Self {
+        self.labels.push(Label {
+            span,
+            message: message.into(),
+            primary: false,
+        });
+        self
+    }
+
+    pub fn with_note(mut self, note: impl Into<String>) -> Self {
+        self.notes.push(note.into());
+        self
+    }
+
+    /// Renders the diagnostic against `source`, which was read from `path`.
+    pub fn render(&self, source: &str, path: &str) -> String {
+        let mut out = String::new();
+        let severity = match self.severity {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        };
+        writeln!(out, "{}: {}", severity, self.message).unwrap();
+
+        let mut labels: Vec<&Label> = self.labels.iter().collect();
+        labels.sort_by_key(|label| (label.span.start, !label.primary));
+
+        let width = labels
+            .iter()
+            .map(|label| label.span.line.to_string().len())
+            .max()
+            .unwrap_or(1);
+        let gutter = " ".repeat(width);
+
+        let location = self
+            .labels
+            .iter()
+            .find(|label| label.primary)
+            .or(labels.first().copied());
+        match location {
+            Some(label) => writeln!(
+                out,
+                "{}--> {}:{}:{}",
+                gutter, path, label.span.line, label.span.column
+            )
+            .unwrap(),
+            None => writeln!(out, "{}--> {}", gutter, path).unwrap(),
+        }
+
+        if !labels.is_empty() {
+            writeln!(out, "{} |", gutter).unwrap();
+        }
+        let mut last_line: Option<usize> = None;
+        for label in &labels {
+            let line_no = label.span.line;
+            if last_line != Some(line_no) {
+                if last_line.is_some_and(|last| line_no > last + 1) {
+                    writeln!(out, "...").unwrap();
+                }
+                writeln!(out, "{:>width$} | {}", line_no, line_text(source, label.span)).unwrap();
+                last_line = Some(line_no);
+            }
+            let (padding, len) = underline(source, label.span);
+            let marker = if label.primary { "^" } else { "-" };
+            writeln!(
+                out,
+                "{} | {}{} {}",
+                gutter,
+                padding,
+                marker.repeat(len),
+                label.message
+            )
+            .unwrap();
+        }
+
+        if !self.notes.is_empty() {
+            writeln!(out, "{} |", gutter).unwrap();
+        }
+        for note in &self.notes {
+            writeln!(out, "{} = note: {}", gutter, note).unwrap();
+        }
+        out
+    }
+}
+
+// the full source line a span starts on, without its newline
+fn line_text(source: &str, span: Span) -> &str {
+    let start = span.start.min(source.len());
+    let line_start = source[..start].rfind('\n').map_or(0, |i| i + 1);
+    let line_end = source[start..].find('\n').map_or(source.len(), |i| start + i);
+    source[line_start..line_end].trim_end_matches('\r')
+}
+
+// whitespace that lines up with the span's first column (tabs are kept so the
+// caret stays aligned) and the number of markers to draw, clipped to the line
+fn underline(source: &str, span: Span) -> (String, usize) {
+    let start = span.start.min(source.len());
+    let line_start = source[..start].rfind('\n').map_or(0, |i| i + 1);
+    let padding = source[line_start..start]
+        .chars()
+        .map(|c| if c == '\t' { '\t' } else { ' ' })
+        .collect();
+    let line_end = source[start..].find('\n').map_or(source.len(), |i| start + i);
+    let end = span.end.clamp(start, line_end);
+    let len = source[start..end].chars().count().max(1);
+    (padding, len)
+}
+
+impl From<&Error> for Diagnostic {
+    fn from(error: &Error) -> Self {
+        let mut diagnostic = Diagnostic::error(error.message());
+        let Some(span) = error.span() else {
+            return diagnostic;
+        };
+        diagnostic = match error {
+            Error::Lex { kind, .. } => match kind {
+                LexErrorKind::InvalidCharacter => {
+                    diagnostic.with_label(span, "not part of the language")
+                }
+                LexErrorKind::IncompleteOperator => diagnostic
+                    .with_label(span, "incomplete operator")
+                    .with_note("comparisons are written '==' and '!='"),
+                LexErrorKind::InvalidNumber => diagnostic.with_label(span, "invalid number literal"),
+                LexErrorKind::UnterminatedComment => diagnostic
+                    .with_label(span, "comment starts here")
+                    .with_note("block comments end with '*/' and may nest"),
+                LexErrorKind::UnterminatedString => diagnostic
+                    .with_label(span, "string starts here")
+                    .with_note("strings end with '\"'"),
+                LexErrorKind::InvalidEscape => diagnostic
+                    .with_label(span, "unknown escape sequence")
+                    .with_note("the escapes are \\n, \\t, \\r, \\0, \\\\ and \\\""),
+            },
+            Error::Parse { kind, .. } => match kind {
+                ParseErrorKind::Unclosed { open, close } => diagnostic
+                    .with_label(span, format!("expected '{}' here", close))
+                    .with_secondary(*open, "unclosed expression starts here"),
+                ParseErrorKind::TrailingTokens => diagnostic
+                    .with_label(span, "extra input after the expression")
+                    .with_note("a program is a single expression"),
+                ParseErrorKind::InvalidPragma => diagnostic
+                    .with_label(span, "invalid pragma")
+                    .with_note(
+                        "pragmas are `#!overflow checked|wrapping|saturating` and \
+                         `#!division integer|rational`, at the top of the program",
+                    ),
+                _ => diagnostic.with_label(span, "unexpected input"),
+            },
+            Error::Compile { kind, .. } => match kind {
+                CompileErrorKind::UndefinedVariable => {
+                    diagnostic.with_label(span, "not found in this scope")
+                }
+                CompileErrorKind::InvalidSyntax => diagnostic.with_label(span, "syntax error here"),
+                CompileErrorKind::TooManyLocals => diagnostic
+                    .with_label(span, "one variable too many")
+                    .with_note(format!("a program can use at most {} variables", MAX_LOCALS)),
+                CompileErrorKind::ArityMismatch => {
+                    diagnostic.with_label(span, "called with the wrong number of arguments")
+                }
+                CompileErrorKind::DuplicateParameter => {
+                    diagnostic.with_label(span, "used as a parameter name more than once")
+                }
+                CompileErrorKind::InvalidAssignment => diagnostic
+                    .with_label(span, "cannot be assigned here")
+                    .with_note("functions keep a copy of the variables they capture, so those cannot be `set`"),
+            },
+            Error::Runtime { kind, .. } => match kind {
+                RuntimeErrorKind::StackDepthExceeded => diagnostic
+                    .with_label(span, "while evaluating this call")
+                    .with_note("check that the recursion reaches its base case"),
+                _ => diagnostic.with_label(span, "while evaluating this"),
+            },
+        };
+        diagnostic
+    }
+}
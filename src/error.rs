@@ -0,0 +1,172 @@
+use std::fmt;
+
+use crate::lexer::Span;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LexErrorKind {
+    InvalidCharacter,
+    IncompleteOperator, // '=' without the '=' that completes it
+    InvalidNumber,
+    UnterminatedComment, // a `/*` with no matching `*/`
+    UnterminatedString,
+    InvalidEscape, // a `\` in a string followed by something it cannot escape
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParseErrorKind {
+    ExpectedToken,
+    // a closing ')' or ']' is missing; `open` is where the unclosed form begins
+    Unclosed { open: Span, close: char },
+    UnexpectedToken,
+    UnexpectedEof,
+    TrailingTokens,
+    InvalidPragma,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompileErrorKind {
+    UndefinedVariable,
+    InvalidSyntax, // an `ExprKind::Error` left behind by parser recovery, or an empty block
+    TooManyLocals,
+    ArityMismatch,
+    DuplicateParameter,
+    InvalidAssignment, // `set` of a function, or of a variable a function captures
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RuntimeErrorKind {
+    StackOverflow,
+    StackUnderflow,
+    DivisionByZero,
+    IntegerOverflow,
+    InvalidLocal,
+    InvalidInstruction,
+    InvalidJump,
+    InvalidCall,    // calling a value that is not a closure
+    ArityMismatch, // calling a closure with the wrong number of arguments
+    StackDepthExceeded, // more nested calls than `VmConfig::max_call_depth`
+    TypeMismatch,
+    ShiftOutOfRange, // a shift amount outside 0..32
+    IndexOutOfBounds,
+    NegativeExponent, // an integer raised to a negative integer power
+}
+
+/// Every failure the pipeline can produce, tagged with the stage it came from.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Error {
+    Lex {
+        kind: LexErrorKind,
+        message: String,
+        span: Option<Span>,
+    },
+    Parse {
+        kind: ParseErrorKind,
+        message: String,
+        span: Option<Span>,
+    },
+    Compile {
+        kind: CompileErrorKind,
+        message: String,
+        span: Option<Span>,
+    },
+    Runtime {
+        kind: RuntimeErrorKind,
+        message: String,
+        span: Option<Span>,
+    },
+}
+
+impl Error {
+    pub fn lex(kind: LexErrorKind, message: impl Into<String>, span: Span) -> Self {
+        Error::Lex {
+            kind,
+            message: message.into(),
+            span: Some(span),
+        }
+    }
+
+    pub fn parse(kind: ParseErrorKind, message: impl Into<String>, span: Span) -> Self {
+        Error::Parse {
+            kind,
+            message: message.into(),
+            span: Some(span),
+        }
+    }
+
+    pub fn compile(kind: CompileErrorKind, message: impl Into<String>, span: Span) -> Self {
+        Error::Compile {
+            kind,
+            message: message.into(),
+            span: Some(span),
+        }
+    }
+
+    // the VM only knows the instruction pointer; `run_program` fills in the span
+    pub fn runtime(kind: RuntimeErrorKind, message: impl Into<String>) -> Self {
+        Error::Runtime {
+            kind,
+            message: message.into(),
+            span: None,
+        }
+    }
+
+    pub fn message(&self) -> &str {
+        match self {
+            Error::Lex { message, .. }
+            | Error::Parse { message, .. }
+            | Error::Compile { message, .. }
+            | Error::Runtime { message, .. } => message,
+        }
+    }
+
+    pub fn span(&self) -> Option<Span> {
+        match self {
+            Error::Lex { span, .. }
+            | Error::Parse { span, .. }
+            | Error::Compile { span, .. }
+            | Error::Runtime { span, .. } => *span,
+        }
+    }
+
+    /// Attach a location if the error does not have one yet.
+    pub fn with_span(mut self, location: Option<Span>) -> Self {
+        match &mut self {
+            Error::Lex { span, .. }
+            | Error::Parse { span, .. }
+            | Error::Compile { span, .. }
+            | Error::Runtime { span, .. } => {
+                if span.is_none() {
+                    *span = location;
+                }
+            }
+        }
+        self
+    }
+
+    fn stage(&self) -> &'static str {
+        match self {
+            Error::Lex { .. } => "Lexer",
+            Error::Parse { .. } => "Parser",
+            Error::Compile { .. } => "Compile",
+            Error::Runtime { .. } => "Runtime",
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.span() {
+            Some(span) => write!(
+                f,
+                "{} error at {}:{}: {}",
+                self.stage(),
+                span.line,
+                span.column,
+                self.message()
+            ),
+            None => write!(f, "{} error: {}", self.stage(), self.message()),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
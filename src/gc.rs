@@ -0,0 +1,120 @@
+//! Mark-and-sweep collection of the closures the VM allocates.
+//!
+//! Strings and lists are reference counted and free themselves, but a closure
+//! value is only an index into the VM's heap, so nothing frees a closure
+//! except this collector.
+
+use std::mem;
+
+use crate::value::Value;
+use crate::vm::Closure;
+
+/// Default number of heap bytes allocated before the first collection.
+pub const GC_THRESHOLD: usize = 1 << 20;
+/// Default factor by which the live heap may grow before the next collection.
+pub const GC_GROWTH: usize = 2;
+
+/// What the collector has done so far.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct GcStats {
+    pub collections: usize,
+    pub objects_freed: usize,
+    pub bytes_freed: usize,
+    // heap bytes in use right now
+    pub bytes_live: usize,
+}
+
+pub struct Heap {
+    // freed slots are `None` and get reused, so live indices never move
+    objects: Vec<Option<Closure>>,
+    free: Vec<usize>,
+    threshold: usize,
+    growth: usize,
+    // collect once `stats.bytes_live` reaches this
+    next_collection: usize,
+    stats: GcStats,
+}
+
+impl Heap {
+    pub fn new(threshold: usize, growth: usize) -> Self {
+        Self {
+            objects: Vec::new(),
+            free: Vec::new(),
+            threshold,
+            growth,
+            next_collection: threshold,
+            stats: GcStats::default(),
+        }
+    }
+
+    pub fn get(&self, index: usize) -> Option<&Closure> {
+        self.objects.get(index)?.as_ref()
+    }
+
+    pub fn stats(&self) -> GcStats {
+        self.stats
+    }
+
+    /// Whether the heap has grown enough since the last collection to be
+    /// worth collecting before the next allocation.
+    pub fn wants_collection(&self) -> bool {
+        self.stats.bytes_live >= self.next_collection
+    }
+
+    pub fn allocate(&mut self, closure: Closure) -> usize {
+        self.stats.bytes_live += size_of_closure(&closure);
+        match self.free.pop() {
+            Some(index) => {
+                self.objects[index] = Some(closure);
+                index
+            }
+            None => {
+                self.objects.push(Some(closure));
+                self.objects.len() - 1
+            }
+        }
+    }
+
+    /// Frees every closure that cannot be reached from `roots`.
+    pub fn collect<'a>(&mut self, roots: impl IntoIterator<Item = &'a Value>) {
+        let mut marked = vec![false; self.objects.len()];
+        let mut pending: Vec<Value> = roots.into_iter().cloned().collect();
+        while let Some(value) = pending.pop() {
+            match value {
+                Value::Closure(index) => {
+                    if marked.get(index).is_none_or(|&seen| seen) {
+                        continue;
+                    }
+                    marked[index] = true;
+                    if let Some(closure) = &self.objects[index] {
+                        pending.extend(closure.captured.iter().cloned());
+                    }
+                }
+                // a list can hold closures, directly or in nested lists
+                Value::List(elements) => pending.extend(elements.iter().cloned()),
+                _ => {}
+            }
+        }
+
+        for (index, slot) in self.objects.iter_mut().enumerate() {
+            if marked[index] {
+                continue;
+            }
+            if let Some(closure) = mem::take(slot) {
+                let size = size_of_closure(&closure);
+                self.stats.objects_freed += 1;
+                self.stats.bytes_freed += size;
+                self.stats.bytes_live -= size;
+                self.free.push(index);
+            }
+        }
+        self.stats.collections += 1;
+        self.next_collection = self.threshold.max(self.stats.bytes_live.saturating_mul(self.growth));
+    }
+}
+
+// the closure and its captured values; strings and lists it captures are
+// shared and not counted
+fn size_of_closure(closure: &Closure) -> usize {
+    mem::size_of::<Closure>() + closure.captured.capacity() * mem::size_of::<Value>()
+}
@@ -1,15 +1,69 @@
+use crate::error::{Error, LexErrorKind};
+
 // TODO: using a simpler repr now might add more later;
 
+/// Location of a piece of source text: a byte range into the input plus the
+/// 1-based line and column where it starts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl Span {
+    /// Span covering everything from the start of `self` to the end of `other`.
+    pub fn to(self, other: Span) -> Span {
+        Span {
+            start: self.start,
+            end: other.end.max(self.end),
+            line: self.line,
+            column: self.column,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Token {
+    pub kind: TokenKind,
+    pub span: Span,
+}
+
+/// Source text that is not a token but that a formatter has to keep.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Trivia {
+    pub kind: TriviaKind,
+    // the comment exactly as written, markers included
+    pub text: String,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TriviaKind {
+    // `#` to the end of the line
+    LineComment,
+    // `/* ... */`, possibly nested and spanning lines
+    BlockComment,
+}
+
 #[derive(Debug, Clone, PartialEq)]
-pub enum Token {
+pub enum TokenKind {
     Number(i32),
+    Float(f64),
+    // the text of a `"..."` literal with its escapes already applied
+    Str(String),
     Plus,
     Minus,
     Star,
     Slash,
     LPara,
     RPara,
+    LBracket,
+    RBracket,
     Define, 
+    Set,
+    Do,
     Ident(String), 
     If,
     Equal, 
@@ -22,11 +76,34 @@ pub enum Token {
     Percent,
     SlashSlash,
     StarStar,
+    Fn,
+    Comma,
+    Amp,
+    Pipe,
+    Caret,
+    Tilde,
+    LessLess,
+    GreaterGreater,
+    // `and`/`&&`, `or`/`||` and `not`/`!`; both spellings lex the same
+    And,
+    Or,
+    Not,
+    True,
+    False,
+    // `#!name value` at the start of a line before any other token; holds
+    // the text after `#!`. Anywhere else `#!` starts an ordinary comment.
+    Pragma(String),
 }
 
 pub struct Lexer {
     input: Vec<char>,
     pos: usize,
+    // byte offset, line and column of `pos`, kept in step by `advance`
+    offset: usize,
+    line: usize,
+    column: usize,
+    // comments skipped by `tokenize`, in source order
+    trivia: Vec<Trivia>,
 }
 
 impl Lexer {
@@ -34,17 +111,65 @@ impl Lexer {
         Self {
             input: input.chars().collect(),
             pos: 0,
+            offset: 0,
+            line: 1,
+            column: 1,
+            trivia: Vec::new(),
         }
     }
 
+    /// The comments `tokenize` skipped over.
+    pub fn trivia(&self) -> &[Trivia] {
+        &self.trivia
+    }
+
     fn peek(&self) -> Option<char> {
         self.input.get(self.pos).copied()
     }
 
+    // the character `n` places after the current one
+    fn peek_nth(&self, n: usize) -> Option<char> {
+        self.input.get(self.pos + n).copied()
+    }
+
     fn advance(&mut self) {
+        if let Some(c) = self.peek() {
+            self.offset += c.len_utf8();
+            if c == '\n' {
+                self.line += 1;
+                self.column = 1;
+            } else {
+                self.column += 1;
+            }
+        }
         self.pos += 1;
     }
 
+    // zero-width span at the current position
+    fn mark(&self) -> Span {
+        Span {
+            start: self.offset,
+            end: self.offset,
+            line: self.line,
+            column: self.column,
+        }
+    }
+
+    // span from `start` up to the current position
+    fn span_from(&self, start: Span) -> Span {
+        Span {
+            end: self.offset,
+            ..start
+        }
+    }
+
+    fn token(&self, kind: TokenKind, start: Span) -> Token {
+        Token {
+            kind,
+            span: self.span_from(start),
+        }
+    }
+
     fn skip_whitespace(&mut self) {
         while let Some(c) = self.peek() {
             if !c.is_whitespace() {
@@ -54,18 +179,231 @@ impl Lexer {
         }
     }
 
-    fn read_number(&mut self) -> i32 {
-        let mut num = 0;
+    // consumes characters up to (not including) the end of the line
+    fn read_line(&mut self) -> String {
+        let mut text = String::new();
         while let Some(c) = self.peek() {
-            if c.is_ascii_digit() {
-                num = num * 10 + (c as i32 - '0' as i32);
+            if c == '\n' {
+                break;
+            }
+            text.push(c);
+            self.advance();
+        }
+        text
+    }
+
+    // `/* ... */`; inner `/*` open a nested comment so commenting out code
+    // that already has comments works
+    fn read_block_comment(&mut self, start: Span) -> Result<String, Error> {
+        let mut text = String::new();
+        let mut depth = 0usize;
+        loop {
+            match (self.peek(), self.peek_nth(1)) {
+                (Some('/'), Some('*')) => depth += 1,
+                (Some('*'), Some('/')) => depth -= 1,
+                (Some(c), _) => {
+                    text.push(c);
+                    self.advance();
+                    continue;
+                }
+                (None, _) => {
+                    return Err(Error::lex(
+                        LexErrorKind::UnterminatedComment,
+                        "Unterminated block comment",
+                        // just the opening `/*`
+                        Span {
+                            end: start.start + 2,
+                            ..start
+                        },
+                    ));
+                }
+            }
+            for _ in 0..2 {
+                text.push(self.peek().unwrap());
+                self.advance();
+            }
+            if depth == 0 {
+                return Ok(text);
+            }
+        }
+    }
+
+    // `"..."`: the string may span lines; `\` escapes the next character
+    fn read_string(&mut self, start: Span) -> Result<String, Error> {
+        self.advance();
+        let mut text = String::new();
+        loop {
+            match self.peek() {
+                Some('"') => {
+                    self.advance();
+                    return Ok(text);
+                }
+                Some('\\') => {
+                    let escape_start = self.mark();
+                    self.advance();
+                    let escaped = match self.peek() {
+                        Some('n') => '\n',
+                        Some('t') => '\t',
+                        Some('r') => '\r',
+                        Some('0') => '\0',
+                        Some('\\') => '\\',
+                        Some('"') => '"',
+                        // an unterminated string is the better error
+                        None => continue,
+                        Some(c) => {
+                            self.advance();
+                            return Err(Error::lex(
+                                LexErrorKind::InvalidEscape,
+                                format!("Unknown escape sequence '\\{}'", c),
+                                self.span_from(escape_start),
+                            ));
+                        }
+                    };
+                    text.push(escaped);
+                    self.advance();
+                }
+                Some(c) => {
+                    text.push(c);
+                    self.advance();
+                }
+                None => {
+                    return Err(Error::lex(
+                        LexErrorKind::UnterminatedString,
+                        "Unterminated string literal",
+                        Span {
+                            end: start.start + 1,
+                            ..start
+                        },
+                    ));
+                }
+            }
+        }
+    }
+
+    fn push_trivia(&mut self, kind: TriviaKind, text: String, start: Span) {
+        let span = self.span_from(start);
+        self.trivia.push(Trivia { kind, text, span });
+    }
+
+    // `_` separators (`1_000_000`) are skipped rather than copied into `text`
+    fn read_digits(&mut self, text: &mut String, radix: u32) {
+        while let Some(c) = self.peek() {
+            if c.is_digit(radix) {
+                text.push(c);
+                self.advance();
+            } else if c == '_' {
                 self.advance();
             } else {
                 break;
             }
         }
+    }
+
+    // integers are plain digits or `0x`/`0o`/`0b` and digits in that base; a
+    // fraction (`3.14`) or an exponent (`1e-9`) makes the literal a float
+    fn read_number(&mut self, start: Span) -> Result<TokenKind, Error> {
+        if self.peek() == Some('0') {
+            let radix = match self.peek_nth(1) {
+                Some('x' | 'X') => Some((16, "hexadecimal")),
+                Some('o' | 'O') => Some((8, "octal")),
+                Some('b' | 'B') => Some((2, "binary")),
+                _ => None,
+            };
+            if let Some((radix, name)) = radix {
+                return self.read_radix_number(start, radix, name);
+            }
+        }
+
+        let mut text = String::new();
+        self.read_digits(&mut text, 10);
+        let mut is_float = false;
+
+        if self.peek() == Some('.') && self.peek_nth(1).is_some_and(|c| c.is_ascii_digit()) {
+            is_float = true;
+            text.push('.');
+            self.advance();
+            self.read_digits(&mut text, 10);
+        }
+
+        if let Some(e @ ('e' | 'E')) = self.peek() {
+            let sign = matches!(self.peek_nth(1), Some('+' | '-'));
+            let digits_at = if sign { 2 } else { 1 };
+            if !self.peek_nth(digits_at).is_some_and(|c| c.is_ascii_digit()) {
+                self.advance();
+                return Err(Error::lex(
+                    LexErrorKind::InvalidNumber,
+                    "Expected digits after the exponent marker",
+                    self.span_from(start),
+                ));
+            }
+            is_float = true;
+            text.push(e);
+            self.advance();
+            if sign {
+                text.push(self.peek().unwrap());
+                self.advance();
+            }
+            self.read_digits(&mut text, 10);
+        }
 
-        num
+        if is_float {
+            // digits, an optional fraction and exponent always parse, but may
+            // be too large for an f64
+            let value: f64 = text.parse().unwrap();
+            if !value.is_finite() {
+                return Err(Error::lex(
+                    LexErrorKind::InvalidNumber,
+                    format!("Float literal {} is too large", text),
+                    self.span_from(start),
+                ));
+            }
+            return Ok(TokenKind::Float(value));
+        }
+        text.parse().map(TokenKind::Number).map_err(|_| {
+            Error::lex(
+                LexErrorKind::InvalidNumber,
+                format!("Integer literal {} does not fit in 32 bits", text),
+                self.span_from(start),
+            )
+        })
+    }
+
+    // `0x1F`, `0o17`, `0b1010`: any 32-bit pattern is accepted, so `0xFFFFFFFF`
+    // is -1, the way a register mask would be written
+    fn read_radix_number(&mut self, start: Span, radix: u32, name: &str) -> Result<TokenKind, Error> {
+        self.advance();
+        let prefix = self.peek().unwrap();
+        self.advance();
+        let mut digits = String::new();
+        self.read_digits(&mut digits, radix);
+
+        if let Some(c) = self.peek().filter(|c| c.is_alphanumeric()) {
+            // take the rest of the literal so the error covers all of it
+            while self.peek().is_some_and(|c| c.is_alphanumeric() || c == '_') {
+                self.advance();
+            }
+            return Err(Error::lex(
+                LexErrorKind::InvalidNumber,
+                format!("Invalid digit '{}' in {} literal", c, name),
+                self.span_from(start),
+            ));
+        }
+        if digits.is_empty() {
+            return Err(Error::lex(
+                LexErrorKind::InvalidNumber,
+                format!("Expected {} digits after 0{}", name, prefix),
+                self.span_from(start),
+            ));
+        }
+        u32::from_str_radix(&digits, radix)
+            .map(|bits| TokenKind::Number(bits as i32))
+            .map_err(|_| {
+                Error::lex(
+                    LexErrorKind::InvalidNumber,
+                    format!("Integer literal 0{}{} does not fit in 32 bits", prefix, digits),
+                    self.span_from(start),
+                )
+            })
     }
 
     fn read_identifier(&mut self) -> String {
@@ -81,104 +419,187 @@ impl Lexer {
         ident
     }
 
-    pub fn tokenize(&mut self) -> Result<Vec<Token>, String> {
+    pub fn tokenize(&mut self) -> Result<Vec<Token>, Error> {
         let mut tokens = Vec::new();
 
         while let Some(c) = self.peek() {
+            let start = self.mark();
             match c {
                 ' ' | '\t' | '\n' => {
                     self.skip_whitespace();
                 }
                 '0'..='9' => {
-                    let num = self.read_number();
-                    tokens.push(Token::Number(num));
+                    let kind = self.read_number(start)?;
+                    tokens.push(self.token(kind, start));
                 }
                 'a'..='z' | 'A'..='Z' | '_' => {
                     let ident = self.read_identifier();
-                    let token = match ident.as_str() {
-                        "define" => Token::Define, 
-                        "if" => Token::If,
-                        "while" => Token::While,
-                        _ => Token::Ident(ident),
+                    let kind = match ident.as_str() {
+                        "define" => TokenKind::Define, 
+                        "set" => TokenKind::Set,
+                        "do" => TokenKind::Do,
+                        "if" => TokenKind::If,
+                        "while" => TokenKind::While,
+                        "fn" => TokenKind::Fn,
+                        "and" => TokenKind::And,
+                        "or" => TokenKind::Or,
+                        "not" => TokenKind::Not,
+                        "true" => TokenKind::True,
+                        "false" => TokenKind::False,
+                        _ => TokenKind::Ident(ident),
                     };
-                    tokens.push(token);
+                    tokens.push(self.token(kind, start));
                 }
                 '+' => {
                     self.advance();
-                    tokens.push(Token::Plus);
+                    tokens.push(self.token(TokenKind::Plus, start));
                 }
                 '-' => {
                     self.advance();
-                    tokens.push(Token::Minus);
+                    tokens.push(self.token(TokenKind::Minus, start));
                 }
                 '*' => {
                     self.advance();
                     if let Some('*') = self.peek() {
                         self.advance();
-                        tokens.push(Token::StarStar);
+                        tokens.push(self.token(TokenKind::StarStar, start));
                     }else {
-                        tokens.push(Token::Star);
+                        tokens.push(self.token(TokenKind::Star, start));
                     }
                 }
+                '/' if self.peek_nth(1) == Some('*') => {
+                    let text = self.read_block_comment(start)?;
+                    self.push_trivia(TriviaKind::BlockComment, text, start);
+                }
                 '/' => {
                     self.advance();
                     if let Some('/') = self.peek() {
                         self.advance();
-                        tokens.push(Token::SlashSlash);
+                        tokens.push(self.token(TokenKind::SlashSlash, start));
                     }else {
-                        tokens.push(Token::Slash);
+                        tokens.push(self.token(TokenKind::Slash, start));
                     }
                 }
                 '%' => {
                     self.advance();
-                    tokens.push(Token::Percent);
+                    tokens.push(self.token(TokenKind::Percent, start));
                 }
                 '(' => {
                     self.advance();
-                    tokens.push(Token::LPara);
+                    tokens.push(self.token(TokenKind::LPara, start));
                 }
                 ')' => {
                     self.advance();
-                    tokens.push(Token::RPara);
+                    tokens.push(self.token(TokenKind::RPara, start));
+                }
+                '[' => {
+                    self.advance();
+                    tokens.push(self.token(TokenKind::LBracket, start));
+                }
+                ']' => {
+                    self.advance();
+                    tokens.push(self.token(TokenKind::RBracket, start));
+                }
+                ',' => {
+                    self.advance();
+                    tokens.push(self.token(TokenKind::Comma, start));
                 }
                 '=' => {
                     self.advance();
                     if let Some('=') = self.peek(){
                         self.advance();
-                        tokens.push(Token::Equal);  
+                        tokens.push(self.token(TokenKind::Equal, start));  
                     }else {
-                        return Err("Expected '=' after = ".into());
+                        return Err(Error::lex(
+                            LexErrorKind::IncompleteOperator,
+                            "Expected '=' after =",
+                            self.span_from(start),
+                        ));
                     }
                 }
                 '!' => {
                     self.advance();
                     if let Some('=') = self.peek() {
                         self.advance();
-                        tokens.push(Token::NotEqual);
+                        tokens.push(self.token(TokenKind::NotEqual, start));
                     }else {
-                        return Err("Expected '=' after !".into());
+                        tokens.push(self.token(TokenKind::Not, start));
+                    }
+                }
+                '&' => {
+                    self.advance();
+                    if let Some('&') = self.peek() {
+                        self.advance();
+                        tokens.push(self.token(TokenKind::And, start));
+                    } else {
+                        tokens.push(self.token(TokenKind::Amp, start));
                     }
                 }
+                '|' => {
+                    self.advance();
+                    if let Some('|') = self.peek() {
+                        self.advance();
+                        tokens.push(self.token(TokenKind::Or, start));
+                    } else {
+                        tokens.push(self.token(TokenKind::Pipe, start));
+                    }
+                }
+                '^' => {
+                    self.advance();
+                    tokens.push(self.token(TokenKind::Caret, start));
+                }
+                '~' => {
+                    self.advance();
+                    tokens.push(self.token(TokenKind::Tilde, start));
+                }
                 '<' => {
                     self.advance();
                     if let Some('=') = self.peek() {
                         self.advance();
-                        tokens.push(Token::LessEq);
+                        tokens.push(self.token(TokenKind::LessEq, start));
+                    } else if let Some('<') = self.peek() {
+                        self.advance();
+                        tokens.push(self.token(TokenKind::LessLess, start));
                     }else {
-                        tokens.push(Token::Less);
+                        tokens.push(self.token(TokenKind::Less, start));
                     }
                 }
                 '>' => { 
                     self.advance();
                     if let Some('=') = self.peek() {
                         self.advance();
-                        tokens.push(Token::GreaterEq);
+                        tokens.push(self.token(TokenKind::GreaterEq, start));
+                    } else if let Some('>') = self.peek() {
+                        self.advance();
+                        tokens.push(self.token(TokenKind::GreaterGreater, start));
                     }else {
-                        tokens.push(Token::Greater);
+                        tokens.push(self.token(TokenKind::Greater, start));
                     }
                 }
+                // every token so far being a pragma means none is real yet
+                '#' if self.peek_nth(1) == Some('!')
+                    && self.column == 1
+                    && matches!(tokens.last(), None | Some(Token { kind: TokenKind::Pragma(_), .. })) =>
+                {
+                    let text = self.read_line();
+                    let text = text[2..].trim().to_string();
+                    tokens.push(self.token(TokenKind::Pragma(text), start));
+                }
+                '"' => {
+                    let text = self.read_string(start)?;
+                    tokens.push(self.token(TokenKind::Str(text), start));
+                }
+                '#' => {
+                    let text = self.read_line();
+                    self.push_trivia(TriviaKind::LineComment, text, start);
+                }
                 _ => {
-                    return Err(format!("Invalid character: '{}'", c));
+                    self.advance();
+                    return Err(Error::lex(
+                        LexErrorKind::InvalidCharacter,
+                        format!("Invalid character: '{}'", c),
+                        self.span_from(start),
+                    ));
                 }
             }
         }
@@ -1,5 +1,11 @@
+pub mod builtins;
 pub mod compiler;
+pub mod diagnostics;
+pub mod error;
+pub mod gc;
 pub mod lexer;
 pub mod parser;
+pub mod resolver;
 pub mod vm;
 pub mod utils;
+pub mod value;
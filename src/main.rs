@@ -1,14 +1,12 @@
 use std::env;
 use std::fs::OpenOptions;
 
-use crate::{compiler::compile, lexer::Lexer, parser::Parser, vm::run_program};
+use expression_solver::{
+    compiler::compile, diagnostics::Diagnostic, lexer::Lexer, parser::Parser, resolver::resolve,
+    vm::run_program,
+};
 
-mod compiler;
 mod input;
-mod lexer;
-mod parser;
-mod utils;
-mod vm;
 
 fn main() {
     let path = env::args().nth(1).expect("Provide file path");
@@ -20,26 +18,47 @@ fn main() {
     let tokens = match lexer.tokenize() {
         Ok(tokens) => tokens,
         Err(e) => {
-            eprintln!("Error {}", e);
+            eprint!("{}", Diagnostic::from(&e).render(&blob, &path));
             return;
         }
     };
 
     let mut parser = Parser::new(tokens);
-    let ast = match parser.parse() {
-        Ok(ast) => ast,
-        Err(e) => {
-            eprintln!("Parser error: {}", e);
-            return;
+    let (ast, errors) = parser.parse_recovering();
+    if !errors.is_empty() {
+        for e in &errors {
+            eprintln!("{}", Diagnostic::from(e).render(&blob, &path));
         }
-    };
+        eprintln!("aborting due to {} syntax error(s)", errors.len());
+        return;
+    }
 
     println!("{:#?}", ast);
 
-    let program = compile(&ast);
+    let resolved = match resolve(ast) {
+        Ok(resolved) => resolved,
+        Err(errors) => {
+            for e in &errors {
+                eprintln!("{}", Diagnostic::from(e).render(&blob, &path));
+            }
+            eprintln!("aborting due to {} error(s)", errors.len());
+            return;
+        }
+    };
+    for warning in &resolved.warnings {
+        eprintln!("{}", warning.render(&blob, &path));
+    }
+
+    let program = match compile(&resolved) {
+        Ok(program) => program,
+        Err(e) => {
+            eprint!("{}", Diagnostic::from(&e).render(&blob, &path));
+            return;
+        }
+    };
 
     println!("\nBYTECODE:");
-    println!("{:?}", program);
+    println!("{:?}", program.code);
 
     let mut log_file = OpenOptions::new()
         .create(true)
@@ -48,9 +67,9 @@ fn main() {
         .open("log.log")
         .expect("Failed to open log file");
 
-    match run_program(program, &mut log_file) {
+    match run_program(&program, &mut log_file) {
         Ok(Some(result)) => println!("\nRESULT = {}", result),
         Ok(None) => println!("Program finished with empty stack"),
-        Err(_) => println!("Runtime error"),
+        Err(e) => eprint!("{}", Diagnostic::from(&e).render(&blob, &path)),
     }
 }
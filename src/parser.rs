@@ -1,8 +1,16 @@
-use crate::lexer::Token;
+use crate::{
+    builtins::Builtin,
+    error::{Error, ParseErrorKind},
+    lexer::{Span, Token, TokenKind},
+    value::{DivisionMode, OverflowMode},
+    resolver::BindingId,
+};
 
 #[derive(Debug)]
 pub enum UnaryOp {
     Neg,
+    Not,
+    BitNot,
 }
 
 #[derive(Debug)]
@@ -20,17 +28,72 @@ pub enum BinaryOp {
     Greater,
     LessEq,
     GreaterEq,
+    BitAnd,
+    BitOr,
+    BitXor,
+    Shl,
+    Shr,
+    // short-circuiting: the right side is skipped once the left decides
+    And,
+    Or,
+}
+
+/// A setting chosen by a `#!name value` line at the top of a program.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Pragma {
+    Overflow(OverflowMode),
+    Division(DivisionMode),
+}
+
+#[derive(Debug)]
+pub struct Param {
+    pub name: String,
+    pub span: Span,
+    pub binding: Option<BindingId>,
+}
+
+#[derive(Debug)]
+pub struct Expr {
+    pub kind: ExprKind,
+    pub span: Span,
+}
+
+impl Expr {
+    pub fn new(kind: ExprKind, span: Span) -> Self {
+        Self { kind, span }
+    }
 }
 
 #[derive(Debug)]
-pub enum Expr {
+pub enum ExprKind {
     Number(i32),
-    Variable(String),
+    Float(f64),
+    Bool(bool),
+    Str(String),
+    // `binding` starts out as None and is filled in by the resolver
+    Variable {
+        name: String,
+        binding: Option<BindingId>,
+    },
     Define {
         name: String,
+        name_span: Span,
+        binding: Option<BindingId>,
         value: Box<Expr>,
         body: Box<Expr>,
     },
+    // do (e1 e2 ... en): evaluates each in order and yields the last
+    Block(Vec<Expr>),
+    // set (name value): overwrites an existing variable; evaluates to `value`.
+    // set (name[index] value) replaces one element of the list in `name` and
+    // evaluates to the updated list
+    Set {
+        name: String,
+        name_span: Span,
+        binding: Option<BindingId>,
+        index: Option<Box<Expr>>,
+        value: Box<Expr>,
+    },
     Unary {
         op: UnaryOp,
         expr: Box<Expr>,
@@ -49,118 +112,409 @@ pub enum Expr {
         condition: Box<Expr>,
         body: Box<Expr>,
     },
+    // fn (name (params...) func_body body): `name` is callable inside `body`
+    Function {
+        name: String,
+        name_span: Span,
+        binding: Option<BindingId>,
+        params: Vec<Param>,
+        func_body: Box<Expr>,
+        body: Box<Expr>,
+    },
+    // [a, b, c]
+    List(Vec<Expr>),
+    // target[index], for lists and strings
+    Index {
+        target: Box<Expr>,
+        index: Box<Expr>,
+    },
+    Call {
+        callee: Box<Expr>,
+        args: Vec<Expr>,
+        // filled in by the resolver when the callee names a builtin
+        builtin: Option<Builtin>,
+    },
+    // a pragma applying to the whole of `body`, which is the rest of the program
+    Pragma {
+        pragma: Pragma,
+        body: Box<Expr>,
+    },
+    // placeholder left where a syntax error was recovered from
+    Error,
 }
 
 pub struct Parser {
     tokens: Vec<Token>,
     pos: usize,
+    // error recovery state, only used by `parse_recovering`
+    recovering: bool,
+    errors: Vec<Error>,
+    synced_at: Option<usize>,
 }
 
 impl Parser {
     pub fn new(tokens: Vec<Token>) -> Self {
-        Self { tokens, pos: 0 }
+        Self {
+            tokens,
+            pos: 0,
+            recovering: false,
+            errors: Vec::new(),
+            synced_at: None,
+        }
     }
 
-    fn peek(&self) -> Option<&Token> {
-        self.tokens.get(self.pos)
+    fn peek(&self) -> Option<&TokenKind> {
+        self.tokens.get(self.pos).map(|tok| &tok.kind)
     }
 
-    fn advance(&mut self) -> Option<&Token> {
+    fn advance(&mut self) -> Option<&TokenKind> {
         let tok = self.tokens.get(self.pos);
         self.pos += 1;
-        tok
+        tok.map(|tok| &tok.kind)
+    }
+
+    // span of the token about to be consumed, or an empty span just past the
+    // last token once the input is exhausted
+    fn peek_span(&self) -> Span {
+        match self.tokens.get(self.pos) {
+            Some(tok) => tok.span,
+            None => self.tokens.last().map_or(Span::default(), |tok| Span {
+                start: tok.span.end,
+                column: tok.span.column + (tok.span.end - tok.span.start),
+                ..tok.span
+            }),
+        }
+    }
+
+    // span of the most recently consumed token
+    fn prev_span(&self) -> Span {
+        self.pos
+            .checked_sub(1)
+            .and_then(|i| self.tokens.get(i))
+            .map_or(Span::default(), |tok| tok.span)
+    }
+
+    // consumes the next token if it is `expected`, otherwise reports `message`
+    // against whatever is there instead
+    fn expect(&mut self, expected: TokenKind, message: &str) -> Result<(), Error> {
+        match self.peek() {
+            Some(tok) if *tok == expected => {
+                self.advance();
+                Ok(())
+            }
+            Some(_) => Err(Error::parse(
+                ParseErrorKind::ExpectedToken,
+                message,
+                self.peek_span(),
+            )),
+            None => Err(Error::parse(
+                ParseErrorKind::UnexpectedEof,
+                message,
+                self.peek_span(),
+            )),
+        }
+    }
+
+    fn expect_ident(&mut self, message: &str) -> Result<(String, Span), Error> {
+        let span = self.peek_span();
+        match self.peek() {
+            Some(TokenKind::Ident(name)) => {
+                let name = name.clone();
+                self.advance();
+                Ok((name, span))
+            }
+            Some(_) => Err(Error::parse(ParseErrorKind::ExpectedToken, message, span)),
+            None => Err(Error::parse(ParseErrorKind::UnexpectedEof, message, span)),
+        }
+    }
+
+    // like `expect(RPara, ..)`, but remembers where the unclosed form began
+    fn expect_closing(&mut self, open: Span, message: &str) -> Result<(), Error> {
+        if let Some(TokenKind::RPara) = self.peek() {
+            self.advance();
+            return Ok(());
+        }
+        Err(Error::parse(
+            ParseErrorKind::Unclosed { open, close: ')' },
+            message,
+            self.peek_span(),
+        ))
+    }
+
+    fn expect_closing_bracket(&mut self, open: Span, message: &str) -> Result<(), Error> {
+        if let Some(TokenKind::RBracket) = self.peek() {
+            self.advance();
+            return Ok(());
+        }
+        Err(Error::parse(
+            ParseErrorKind::Unclosed { open, close: ']' },
+            message,
+            self.peek_span(),
+        ))
     }
 
     //to make sure we return exactly one token from the entire collection;
-    pub fn parse(&mut self) -> Result<Expr, String> {
-        let expr = self.parse_expr()?;
+    pub fn parse(&mut self) -> Result<Expr, Error> {
+        let mut pragmas = Vec::new();
+        while let Some(TokenKind::Pragma(_)) = self.peek() {
+            pragmas.push(self.parse_pragma()?);
+        }
+        let expr = wrap_pragmas(pragmas, self.parse_expr()?);
 
         if self.peek().is_some() {
-            return Err("Unexpected Tokens after Parsing".into());
+            return Err(Error::parse(
+                ParseErrorKind::TrailingTokens,
+                "Unexpected Tokens after Parsing",
+                self.peek_span(),
+            ));
         }
 
         Ok(expr)
     }
 
-    pub fn parse_expr(&mut self) -> Result<Expr, String> {
-        if let Some(Token::Define) = self.peek() {
+    /// Parses the whole input without stopping at the first syntax error.
+    /// Broken sub-expressions are replaced by `ExprKind::Error` nodes and every
+    /// error found is returned alongside the partial tree.
+    pub fn parse_recovering(&mut self) -> (Expr, Vec<Error>) {
+        self.recovering = true;
+        let mut pragmas = Vec::new();
+        while let Some(TokenKind::Pragma(_)) = self.peek() {
+            match self.parse_pragma() {
+                Ok(pragma) => pragmas.push(pragma),
+                Err(error) => self.errors.push(error),
+            }
+        }
+        let expr = self
+            .parse_expr()
+            .unwrap_or_else(|error| self.recover(error));
+        let expr = wrap_pragmas(pragmas, expr);
+
+        if self.peek().is_some() {
+            self.errors.push(Error::parse(
+                ParseErrorKind::TrailingTokens,
+                "Unexpected Tokens after Parsing",
+                self.peek_span(),
+            ));
+            // keep scanning the leftovers so their errors are reported too
+            while let Some(tok) = self.peek() {
+                if *tok == TokenKind::RPara {
+                    self.advance();
+                } else {
+                    self.synced_at = Some(self.pos);
+                    self.parse_expr().unwrap_or_else(|error| self.recover(error));
+                }
+            }
+        }
+
+        self.recovering = false;
+        (expr, std::mem::take(&mut self.errors))
+    }
+
+    fn parse_pragma(&mut self) -> Result<(Pragma, Span), Error> {
+        let span = self.peek_span();
+        let Some(TokenKind::Pragma(text)) = self.advance() else {
+            unreachable!("parse_pragma called on a non-pragma token");
+        };
+        let words: Vec<&str> = text.split_whitespace().collect();
+        match words.as_slice() {
+            ["overflow", mode] => match OverflowMode::from_name(mode) {
+                Some(mode) => Ok((Pragma::Overflow(mode), span)),
+                None => Err(Error::parse(
+                    ParseErrorKind::InvalidPragma,
+                    format!(
+                        "Unknown overflow mode '{}': expected checked, wrapping or saturating",
+                        mode
+                    ),
+                    span,
+                )),
+            },
+            ["division", mode] => match DivisionMode::from_name(mode) {
+                Some(mode) => Ok((Pragma::Division(mode), span)),
+                None => Err(Error::parse(
+                    ParseErrorKind::InvalidPragma,
+                    format!(
+                        "Unknown division mode '{}': expected integer or rational",
+                        mode
+                    ),
+                    span,
+                )),
+            },
+            _ => Err(Error::parse(
+                ParseErrorKind::InvalidPragma,
+                format!("Unknown pragma '#!{}'", text),
+                span,
+            )),
+        }
+    }
+
+    // records `error` (unless nothing was consumed since the last recovery, in
+    // which case it is fallout from that one) and skips ahead to a point where
+    // parsing can resume
+    fn recover(&mut self, error: Error) -> Expr {
+        let span = error.span().unwrap_or_else(|| self.peek_span());
+        if self.synced_at != Some(self.pos) {
+            self.errors.push(error);
+        }
+        self.synchronize();
+        self.synced_at = Some(self.pos);
+        Expr::new(ExprKind::Error, span.to(self.prev_span()))
+    }
+
+    // skips tokens up to the ')' that closes the enclosing form or the next
+    // keyword that starts a new expression, stepping over balanced parentheses
+    fn synchronize(&mut self) {
+        let mut depth = 0usize;
+        while let Some(tok) = self.peek() {
+            match tok {
+                TokenKind::LPara => depth += 1,
+                TokenKind::RPara if depth == 0 => return,
+                TokenKind::RPara => depth -= 1,
+                TokenKind::Define
+                | TokenKind::Set
+                | TokenKind::Do
+                | TokenKind::If | TokenKind::While | TokenKind::Fn
+                    if depth == 0 =>
+                {
+                    return;
+                }
+                _ => {}
+            }
+            self.advance();
+        }
+    }
+
+    pub fn parse_expr(&mut self) -> Result<Expr, Error> {
+        match self.parse_form() {
+            Err(error) if self.recovering => Ok(self.recover(error)),
+            result => result,
+        }
+    }
+
+    fn parse_form(&mut self) -> Result<Expr, Error> {
+        if let Some(TokenKind::Define) = self.peek() {
             return self.parse_let();
         }
-        if let Some(Token::If) = self.peek() {
+        if let Some(TokenKind::Set) = self.peek() {
+            return self.parse_set();
+        }
+        if let Some(TokenKind::Do) = self.peek() {
+            return self.parse_block();
+        }
+        if let Some(TokenKind::If) = self.peek() {
             return self.parse_if();
         }
 
-        if let Some(Token::While) = self.peek() {
+        if let Some(TokenKind::While) = self.peek() {
             return self.parse_while();
         }
-        self.parse_comparison()
+
+        if let Some(TokenKind::Fn) = self.peek() {
+            return self.parse_fn();
+        }
+        self.parse_or()
     }
 
-    fn parse_while(&mut self) -> Result<Expr, String> {
-        self.advance();
-        match self.advance() {
-            Some(Token::LPara) => {}
-            _ => return Err("Expected '(' after 'while' ".into()),
+    fn parse_or(&mut self) -> Result<Expr, Error> {
+        let mut expr = self.parse_and()?;
+        while let Some(TokenKind::Or) = self.peek() {
+            self.advance();
+            let right = self.parse_and()?;
+            let span = expr.span.to(right.span);
+            expr = Expr::new(
+                ExprKind::Binary {
+                    left: Box::new(expr),
+                    op: BinaryOp::Or,
+                    right: Box::new(right),
+                },
+                span,
+            )
         }
+        Ok(expr)
+    }
+
+    fn parse_and(&mut self) -> Result<Expr, Error> {
+        let mut expr = self.parse_comparison()?;
+        while let Some(TokenKind::And) = self.peek() {
+            self.advance();
+            let right = self.parse_comparison()?;
+            let span = expr.span.to(right.span);
+            expr = Expr::new(
+                ExprKind::Binary {
+                    left: Box::new(expr),
+                    op: BinaryOp::And,
+                    right: Box::new(right),
+                },
+                span,
+            )
+        }
+        Ok(expr)
+    }
+
+    fn parse_while(&mut self) -> Result<Expr, Error> {
+        let start = self.peek_span();
+        self.advance();
+        self.expect(TokenKind::LPara, "Expected '(' after 'while'")?;
         let condition = self.parse_expr()?;
         let body = self.parse_expr()?;
 
-        match self.advance() {
-            Some(Token::RPara) => {}
-            _ => return Err("Expected ')' to end 'while' ".into()),
-        }
+        self.expect_closing(start, "Expected ')' to end 'while'")?;
 
-        Ok(Expr::While {
-            condition: Box::new(condition),
-            body: Box::new(body),
-        })
+        Ok(Expr::new(
+            ExprKind::While {
+                condition: Box::new(condition),
+                body: Box::new(body),
+            },
+            start.to(self.prev_span()),
+        ))
     }
 
-    fn parse_if(&mut self) -> Result<Expr, String> {
+    fn parse_if(&mut self) -> Result<Expr, Error> {
+        let start = self.peek_span();
         self.advance();
-        match self.advance() {
-            Some(Token::LPara) => {}
-            _ => return Err("Expected '(' after 'if' ".into()),
-        }
+        self.expect(TokenKind::LPara, "Expected '(' after 'if'")?;
         let condition = self.parse_expr()?;
         let then_branch = self.parse_expr()?;
         let else_branch = self.parse_expr()?;
-        match self.advance() {
-            Some(Token::RPara) => {}
-            _ => return Err("Expected ')' to close 'if' ".into()),
-        }
-        Ok(Expr::If {
-            condition: Box::new(condition),
-            then_branch: Box::new(then_branch),
-            else_branch: Box::new(else_branch),
-        })
+        self.expect_closing(start, "Expected ')' to close 'if'")?;
+        Ok(Expr::new(
+            ExprKind::If {
+                condition: Box::new(condition),
+                then_branch: Box::new(then_branch),
+                else_branch: Box::new(else_branch),
+            },
+            start.to(self.prev_span()),
+        ))
     }
 
-    fn parse_comparison(&mut self) -> Result<Expr, String> {
-        let mut expr = self.parse_additive()?;
+    fn parse_comparison(&mut self) -> Result<Expr, Error> {
+        let mut expr = self.parse_bit_or()?;
         while let Some(tok) = self.peek() {
             match tok {
-                Token::Equal
-                | Token::NotEqual
-                | Token::Greater
-                | Token::GreaterEq
-                | Token::Less
-                | Token::LessEq => {
+                TokenKind::Equal
+                | TokenKind::NotEqual
+                | TokenKind::Greater
+                | TokenKind::GreaterEq
+                | TokenKind::Less
+                | TokenKind::LessEq => {
                     let op = match self.advance().unwrap() {
-                        Token::Equal => BinaryOp::Equal,
-                        Token::NotEqual => BinaryOp::NotEqual,
-                        Token::Less => BinaryOp::Less,
-                        Token::Greater => BinaryOp::Greater,
-                        Token::LessEq => BinaryOp::LessEq,
-                        Token::GreaterEq => BinaryOp::GreaterEq,
+                        TokenKind::Equal => BinaryOp::Equal,
+                        TokenKind::NotEqual => BinaryOp::NotEqual,
+                        TokenKind::Less => BinaryOp::Less,
+                        TokenKind::Greater => BinaryOp::Greater,
+                        TokenKind::LessEq => BinaryOp::LessEq,
+                        TokenKind::GreaterEq => BinaryOp::GreaterEq,
                         _ => unreachable!(),
                     };
-                    let right = self.parse_additive()?;
-                    expr = Expr::Binary {
-                        left: Box::new(expr),
-                        op,
-                        right: Box::new(right),
-                    }
+                    let right = self.parse_bit_or()?;
+                    let span = expr.span.to(right.span);
+                    expr = Expr::new(
+                        ExprKind::Binary {
+                            left: Box::new(expr),
+                            op,
+                            right: Box::new(right),
+                        },
+                        span,
+                    )
                 }
                 _ => break,
             }
@@ -168,22 +522,73 @@ impl Parser {
         Ok(expr)
     }
 
-    fn parse_additive(&mut self) -> Result<Expr, String> {
+    // `|` < `^` < `&` < shifts, all binding looser than arithmetic and tighter
+    // than comparisons, so `x & mask == 0` means `(x & mask) == 0`
+    fn parse_bit_or(&mut self) -> Result<Expr, Error> {
+        let mut expr = self.parse_bit_xor()?;
+        while let Some(TokenKind::Pipe) = self.peek() {
+            self.advance();
+            let right = self.parse_bit_xor()?;
+            expr = binary(expr, BinaryOp::BitOr, right);
+        }
+        Ok(expr)
+    }
+
+    fn parse_bit_xor(&mut self) -> Result<Expr, Error> {
+        let mut expr = self.parse_bit_and()?;
+        while let Some(TokenKind::Caret) = self.peek() {
+            self.advance();
+            let right = self.parse_bit_and()?;
+            expr = binary(expr, BinaryOp::BitXor, right);
+        }
+        Ok(expr)
+    }
+
+    fn parse_bit_and(&mut self) -> Result<Expr, Error> {
+        let mut expr = self.parse_shift()?;
+        while let Some(TokenKind::Amp) = self.peek() {
+            self.advance();
+            let right = self.parse_shift()?;
+            expr = binary(expr, BinaryOp::BitAnd, right);
+        }
+        Ok(expr)
+    }
+
+    fn parse_shift(&mut self) -> Result<Expr, Error> {
+        let mut expr = self.parse_additive()?;
+        while let Some(tok) = self.peek() {
+            let op = match tok {
+                TokenKind::LessLess => BinaryOp::Shl,
+                TokenKind::GreaterGreater => BinaryOp::Shr,
+                _ => break,
+            };
+            self.advance();
+            let right = self.parse_additive()?;
+            expr = binary(expr, op, right);
+        }
+        Ok(expr)
+    }
+
+    fn parse_additive(&mut self) -> Result<Expr, Error> {
         let mut expr = self.parse_term()?;
         while let Some(tok) = self.peek() {
             match tok {
-                Token::Minus | Token::Plus => {
+                TokenKind::Minus | TokenKind::Plus => {
                     let op = match self.advance().unwrap() {
-                        Token::Plus => BinaryOp::Add,
-                        Token::Minus => BinaryOp::Sub,
+                        TokenKind::Plus => BinaryOp::Add,
+                        TokenKind::Minus => BinaryOp::Sub,
                         _ => unreachable!(),
                     };
                     let right = self.parse_term()?;
-                    expr = Expr::Binary {
-                        left: Box::new(expr),
-                        op,
-                        right: Box::new(right),
-                    }
+                    let span = expr.span.to(right.span);
+                    expr = Expr::new(
+                        ExprKind::Binary {
+                            left: Box::new(expr),
+                            op,
+                            right: Box::new(right),
+                        },
+                        span,
+                    )
                 }
                 _ => break,
             }
@@ -191,53 +596,171 @@ impl Parser {
         Ok(expr)
     }
 
-    pub fn parse_let(&mut self) -> Result<Expr, String> {
+    pub fn parse_let(&mut self) -> Result<Expr, Error> {
+        let start = self.peek_span();
         // this consumes 'define'
         self.advance();
 
-        match self.advance() {
-            Some(Token::LPara) => {}
-            _ => return Err("Expected '(' after 'define' ".into()),
+        self.expect(TokenKind::LPara, "Expected '(' after 'define'")?;
+        let (name, name_span) = self.expect_ident("Expected variable name after 'define ('")?;
+
+        let value = self.parse_expr()?;
+        let body = self.parse_expr()?;
+
+        self.expect_closing(start, "Expected ')' to close define expression")?;
+
+        Ok(Expr::new(
+            ExprKind::Define {
+                name,
+                name_span,
+                binding: None,
+                value: Box::new(value),
+                body: Box::new(body),
+            },
+            start.to(self.prev_span()),
+        ))
+    }
+
+    fn parse_block(&mut self) -> Result<Expr, Error> {
+        let start = self.peek_span();
+        // this consumes 'do'
+        self.advance();
+
+        self.expect(TokenKind::LPara, "Expected '(' after 'do'")?;
+        // at least one expression, so a block always has a value
+        let mut exprs = vec![self.parse_expr()?];
+        while !matches!(self.peek(), Some(TokenKind::RPara) | None) {
+            exprs.push(self.parse_expr()?);
         }
-        let name = match self.advance() {
-            Some(Token::Ident(n)) => n.clone(),
-            _ => return Err("Expected variable name after 'define (' ".into()),
-        };
+        self.expect_closing(start, "Expected ')' to close 'do'")?;
 
+        Ok(Expr::new(ExprKind::Block(exprs), start.to(self.prev_span())))
+    }
+
+    fn parse_set(&mut self) -> Result<Expr, Error> {
+        let start = self.peek_span();
+        // this consumes 'set'
+        self.advance();
+
+        self.expect(TokenKind::LPara, "Expected '(' after 'set'")?;
+        let (name, name_span) = self.expect_ident("Expected variable name after 'set ('")?;
+        let index = if self.at_index_bracket() {
+            let open = self.peek_span();
+            self.advance();
+            let index = self.parse_expr()?;
+            self.expect_closing_bracket(open, "Expected ']' to close the index")?;
+            Some(Box::new(index))
+        } else {
+            None
+        };
         let value = self.parse_expr()?;
+        self.expect_closing(start, "Expected ')' to close set expression")?;
+
+        Ok(Expr::new(
+            ExprKind::Set {
+                name,
+                name_span,
+                binding: None,
+                index,
+                value: Box::new(value),
+            },
+            start.to(self.prev_span()),
+        ))
+    }
+
+    fn parse_fn(&mut self) -> Result<Expr, Error> {
+        let start = self.peek_span();
+        // this consumes 'fn'
+        self.advance();
+
+        self.expect(TokenKind::LPara, "Expected '(' after 'fn'")?;
+        let (name, name_span) = self.expect_ident("Expected function name after 'fn ('")?;
+
+        let params_start = self.peek_span();
+        self.expect(TokenKind::LPara, "Expected '(' to start the parameter list")?;
+        let mut params = Vec::new();
+        while let Some(TokenKind::Ident(param)) = self.peek() {
+            params.push(Param {
+                name: param.clone(),
+                span: self.peek_span(),
+                binding: None,
+            });
+            self.advance();
+        }
+        self.expect_closing(params_start, "Expected ')' to close the parameter list")?;
+
+        let func_body = self.parse_expr()?;
         let body = self.parse_expr()?;
 
-        match self.advance() {
-            Some(Token::RPara) => {}
-            _ => return Err("Expected ')' to close define expression".into()),
+        self.expect_closing(start, "Expected ')' to close fn expression")?;
+
+        Ok(Expr::new(
+            ExprKind::Function {
+                name,
+                name_span,
+                binding: None,
+                params,
+                func_body: Box::new(func_body),
+                body: Box::new(body),
+            },
+            start.to(self.prev_span()),
+        ))
+    }
+
+    // a '(' glued to the end of the previous token starts an argument list:
+    // `f(x)` is a call while `f (x)` is still two separate expressions
+    fn at_call_paren(&self) -> bool {
+        matches!(self.peek(), Some(TokenKind::LPara)) && self.peek_span().start == self.prev_span().end
+    }
+
+    fn parse_call(&mut self, callee: Expr) -> Result<Expr, Error> {
+        let open = self.peek_span();
+        self.advance();
+
+        let mut args = Vec::new();
+        if !matches!(self.peek(), Some(TokenKind::RPara)) {
+            args.push(self.parse_expr()?);
+            while let Some(TokenKind::Comma) = self.peek() {
+                self.advance();
+                args.push(self.parse_expr()?);
+            }
         }
+        self.expect_closing(open, "Expected ')' to close the argument list")?;
 
-        Ok(Expr::Define {
-            name,
-            value: Box::new(value),
-            body: Box::new(body),
-        })
+        let span = callee.span.to(self.prev_span());
+        Ok(Expr::new(
+            ExprKind::Call {
+                callee: Box::new(callee),
+                args,
+                builtin: None,
+            },
+            span,
+        ))
     }
 
-    fn parse_term(&mut self) -> Result<Expr, String> {
+    fn parse_term(&mut self) -> Result<Expr, Error> {
         let mut expr = self.parse_exponent()?;
         while let Some(tok) = self.peek() {
             match tok {
-                Token::Slash | Token::Star | Token::Percent | Token::SlashSlash => {
+                TokenKind::Slash | TokenKind::Star | TokenKind::Percent | TokenKind::SlashSlash => {
                     let op = match self.advance().unwrap() {
-                        Token::Star => BinaryOp::Mul,
-                        Token::Slash => BinaryOp::Div,
-                        Token::Percent => BinaryOp::Mod,
-                        Token::SlashSlash => BinaryOp::FloorDiv,
+                        TokenKind::Star => BinaryOp::Mul,
+                        TokenKind::Slash => BinaryOp::Div,
+                        TokenKind::Percent => BinaryOp::Mod,
+                        TokenKind::SlashSlash => BinaryOp::FloorDiv,
                         _ => unreachable!(),
                     };
 
                     let right = self.parse_exponent()?;
-                    expr = Expr::Binary {
-                        left: Box::new(expr),
-                        op,
-                        right: Box::new(right),
-                    };
+                    let span = expr.span.to(right.span);
+                    expr = Expr::new(
+                        ExprKind::Binary {
+                            left: Box::new(expr),
+                            op,
+                            right: Box::new(right),
+                        },
+                        span,
+                    );
                 }
                 _ => break,
             }
@@ -245,18 +768,22 @@ impl Parser {
         Ok(expr)
     }
 
-    fn parse_exponent(&mut self) -> Result<Expr, String> {
+    fn parse_exponent(&mut self) -> Result<Expr, Error> {
         let mut expr = self.parse_unary()?;
         while let Some(tok) = self.peek() {
             match tok {
-                Token::StarStar => {
+                TokenKind::StarStar => {
                     self.advance();
                     let right = self.parse_unary()?;
-                    expr = Expr::Binary {
-                        left: Box::new(expr),
-                        op: BinaryOp::Expn,
-                        right: Box::new(right),
-                    }
+                    let span = expr.span.to(right.span);
+                    expr = Expr::new(
+                        ExprKind::Binary {
+                            left: Box::new(expr),
+                            op: BinaryOp::Expn,
+                            right: Box::new(right),
+                        },
+                        span,
+                    )
                 }
                 _ => break,
             }
@@ -264,32 +791,163 @@ impl Parser {
         Ok(expr)
     }
 
-    fn parse_unary(&mut self) -> Result<Expr, String> {
-        if let Some(Token::Minus) = self.peek() {
-            self.advance();
-            let expr = self.parse_unary()?;
-            Ok(Expr::Unary {
-                op: UnaryOp::Neg,
+    fn parse_unary(&mut self) -> Result<Expr, Error> {
+        let op = match self.peek() {
+            Some(TokenKind::Minus) => UnaryOp::Neg,
+            Some(TokenKind::Not) => UnaryOp::Not,
+            Some(TokenKind::Tilde) => UnaryOp::BitNot,
+            _ => return self.parse_primary(),
+        };
+        let start = self.peek_span();
+        self.advance();
+        let expr = self.parse_unary()?;
+        let span = start.to(expr.span);
+        Ok(Expr::new(
+            ExprKind::Unary {
+                op,
                 expr: Box::new(expr),
-            })
-        } else {
-            self.parse_primary()
-        }
+            },
+            span,
+        ))
     }
 
-    fn parse_primary(&mut self) -> Result<Expr, String> {
-        match self.advance() {
-            Some(Token::Number(n)) => Ok(Expr::Number(*n)),
-            Some(Token::Ident(name)) => Ok(Expr::Variable(name.clone())),
-            Some(Token::LPara) => {
+    fn parse_primary(&mut self) -> Result<Expr, Error> {
+        let span = self.peek_span();
+        // a bad token is left in place so error recovery can decide what to skip
+        let mut expr = match self.peek() {
+            Some(TokenKind::Number(n)) => {
+                let expr = Expr::new(ExprKind::Number(*n), span);
+                self.advance();
+                expr
+            }
+            Some(TokenKind::Float(x)) => {
+                let expr = Expr::new(ExprKind::Float(*x), span);
+                self.advance();
+                expr
+            }
+            Some(TokenKind::Str(text)) => {
+                let expr = Expr::new(ExprKind::Str(text.clone()), span);
+                self.advance();
+                expr
+            }
+            Some(TokenKind::True) | Some(TokenKind::False) => {
+                let value = matches!(self.peek(), Some(TokenKind::True));
+                let expr = Expr::new(ExprKind::Bool(value), span);
+                self.advance();
+                expr
+            }
+            Some(TokenKind::Ident(name)) => {
+                let expr = Expr::new(
+                    ExprKind::Variable {
+                        name: name.clone(),
+                        binding: None,
+                    },
+                    span,
+                );
+                self.advance();
+                expr
+            }
+            Some(TokenKind::LPara) => {
+                self.advance();
                 let expr = self.parse_expr()?;
-                match self.advance() {
-                    Some(Token::RPara) => Ok(expr),
-                    _ => Err("Expected ')'".into()),
-                }
+                self.expect_closing(span, "Expected ')'")?;
+                expr
+            }
+            Some(TokenKind::LBracket) => self.parse_list()?,
+            Some(tok) => {
+                return Err(Error::parse(
+                    ParseErrorKind::UnexpectedToken,
+                    format!("Unexpected token: {:?}", tok),
+                    span,
+                ));
+            }
+            None => {
+                return Err(Error::parse(
+                    ParseErrorKind::UnexpectedEof,
+                    "Unexpected end of input",
+                    span,
+                ));
+            }
+        };
+
+        // only names and the results of calls or indexes take a postfix, so
+        // `if (1 (2)(3))` keeps its three separate expressions
+        while matches!(
+            expr.kind,
+            ExprKind::Variable { .. } | ExprKind::Call { .. } | ExprKind::Index { .. }
+        ) {
+            if self.at_call_paren() {
+                expr = self.parse_call(expr)?;
+            } else if self.at_index_bracket() {
+                expr = self.parse_index(expr)?;
+            } else {
+                break;
             }
-            Some(tok) => Err(format!("Unexpected token: {:?}", tok)),
-            None => Err("Unexpected end of input".into()),
         }
+        Ok(expr)
     }
+
+    fn parse_list(&mut self) -> Result<Expr, Error> {
+        let open = self.peek_span();
+        self.advance();
+
+        let mut elements = Vec::new();
+        if !matches!(self.peek(), Some(TokenKind::RBracket)) {
+            elements.push(self.parse_expr()?);
+            while let Some(TokenKind::Comma) = self.peek() {
+                self.advance();
+                elements.push(self.parse_expr()?);
+            }
+        }
+        self.expect_closing_bracket(open, "Expected ']' to close the list")?;
+        Ok(Expr::new(ExprKind::List(elements), open.to(self.prev_span())))
+    }
+
+    // like a call's '(', the '[' of an index has to touch what it indexes, so
+    // `if (c [1] [2])` still reads as a condition and two lists
+    fn at_index_bracket(&self) -> bool {
+        matches!(self.peek(), Some(TokenKind::LBracket)) && self.peek_span().start == self.prev_span().end
+    }
+
+    fn parse_index(&mut self, target: Expr) -> Result<Expr, Error> {
+        let open = self.peek_span();
+        self.advance();
+        let index = self.parse_expr()?;
+        self.expect_closing_bracket(open, "Expected ']' to close the index")?;
+
+        let span = target.span.to(self.prev_span());
+        Ok(Expr::new(
+            ExprKind::Index {
+                target: Box::new(target),
+                index: Box::new(index),
+            },
+            span,
+        ))
+    }
+}
+
+fn binary(left: Expr, op: BinaryOp, right: Expr) -> Expr {
+    let span = left.span.to(right.span);
+    Expr::new(
+        ExprKind::Binary {
+            left: Box::new(left),
+            op,
+            right: Box::new(right),
+        },
+        span,
+    )
+}
+
+// the first pragma ends up outermost
+fn wrap_pragmas(pragmas: Vec<(Pragma, Span)>, expr: Expr) -> Expr {
+    pragmas.into_iter().rev().fold(expr, |body, (pragma, span)| {
+        let span = span.to(body.span);
+        Expr::new(
+            ExprKind::Pragma {
+                pragma,
+                body: Box::new(body),
+            },
+            span,
+        )
+    })
 }
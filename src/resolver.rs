@@ -0,0 +1,401 @@
+use crate::{
+    builtins::Builtin,
+    diagnostics::Diagnostic,
+    error::{CompileErrorKind, Error},
+    lexer::Span,
+    parser::{Expr, ExprKind, Param},
+};
+
+/// Identifies one variable binding. After resolution every `Define` and
+/// `Variable` node that refers to the same variable carries the same id.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct BindingId(pub usize);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BindingKind {
+    Variable,
+    Function { arity: usize },
+}
+
+#[derive(Debug, Clone)]
+pub struct Binding {
+    pub name: String,
+    pub span: Span,
+    pub uses: usize,
+    pub kind: BindingKind,
+    // how many function bodies enclose the binding; 0 for the top level
+    pub depth: usize,
+    // read from inside a function nested deeper than the binding
+    pub captured: bool,
+}
+
+/// The AST with every binding filled in, ready for the compiler, plus the
+/// warnings found while resolving it.
+#[derive(Debug)]
+pub struct Resolved {
+    pub expr: Expr,
+    pub bindings: Vec<Binding>,
+    pub warnings: Vec<Diagnostic>,
+}
+
+pub struct Resolver {
+    // bindings currently in scope, innermost last
+    scopes: Vec<BindingId>,
+    bindings: Vec<Binding>,
+    errors: Vec<Error>,
+    warnings: Vec<Diagnostic>,
+    depth: usize,
+    // every `set` target, checked once all captures are known
+    assignments: Vec<(BindingId, Span)>,
+}
+
+impl Default for Resolver {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Resolver {
+    pub fn new() -> Self {
+        Self {
+            scopes: Vec::new(),
+            bindings: Vec::new(),
+            errors: Vec::new(),
+            warnings: Vec::new(),
+            depth: 0,
+            assignments: Vec::new(),
+        }
+    }
+
+    fn lookup(&self, name: &str) -> Option<BindingId> {
+        self.scopes
+            .iter()
+            .rev()
+            .find(|id| self.bindings[id.0].name == name)
+            .copied()
+    }
+
+    fn declare(&mut self, name: &str, span: Span, kind: BindingKind) -> BindingId {
+        let id = BindingId(self.bindings.len());
+        self.bindings.push(Binding {
+            name: name.to_string(),
+            span,
+            uses: 0,
+            kind,
+            depth: self.depth,
+            captured: false,
+        });
+        id
+    }
+
+    fn error(&mut self, kind: CompileErrorKind, message: String, span: Span) {
+        self.errors.push(Error::compile(kind, message, span));
+    }
+
+    fn warn_unused(&mut self, id: BindingId) {
+        let binding = &self.bindings[id.0];
+        if binding.uses > 0 || binding.name.starts_with('_') {
+            return;
+        }
+        let (name, span) = (binding.name.clone(), binding.span);
+        let what = match binding.kind {
+            BindingKind::Variable => "variable",
+            BindingKind::Function { .. } => "function",
+        };
+        self.warnings.push(
+            Diagnostic::warning(format!("unused {} `{}`", what, name))
+                .with_label(span, "never read")
+                .with_note(format!("rename it to `_{}` if this is intentional", name)),
+        );
+    }
+
+    // a new binding hides any outer one of the same name until its scope ends
+    fn warn_shadowed(&mut self, name: &str, span: Span) {
+        if name.starts_with('_') {
+            return;
+        }
+        let Some(outer) = self.lookup(name) else {
+            return;
+        };
+        self.warnings.push(
+            Diagnostic::warning(format!("`{}` shadows an existing binding", name))
+                .with_label(span, "this hides the outer binding")
+                .with_secondary(self.bindings[outer.0].span, "first defined here")
+                .with_note(format!(
+                    "the outer `{}` cannot be read or `set` in this scope",
+                    name
+                )),
+        );
+    }
+
+    // the binding `set (name ...)` writes to. Closures capture by value, so a
+    // variable from outside the current function cannot be assigned: the
+    // write would only reach this call's copy. Assigning a variable that some
+    // function captures is caught later, by `check_assignments`
+    fn resolve_assignment(&mut self, name: &str, span: Span) -> Option<BindingId> {
+        let Some(id) = self.lookup(name) else {
+            let message = format!("Cannot assign to undeclared variable `{}`", name);
+            self.error(CompileErrorKind::UndefinedVariable, message, span);
+            return None;
+        };
+        let target = &self.bindings[id.0];
+        let message = if let BindingKind::Function { .. } = target.kind {
+            format!("Cannot assign to function `{}`", name)
+        } else if target.depth < self.depth {
+            format!("Cannot assign to `{}`, which this function captures from outside", name)
+        } else {
+            self.assignments.push((id, span));
+            return Some(id);
+        };
+        self.error(CompileErrorKind::InvalidAssignment, message, span);
+        None
+    }
+
+    // a closure keeps the value a captured variable had when it was made,
+    // while a direct call reads the current one, so after a `set` the same
+    // function could see two different values
+    fn check_assignments(&mut self) {
+        for (id, span) in std::mem::take(&mut self.assignments) {
+            let target = &self.bindings[id.0];
+            if target.captured {
+                let message = format!("Cannot assign to `{}`, which a function captures", target.name);
+                self.error(CompileErrorKind::InvalidAssignment, message, span);
+            }
+        }
+    }
+
+    fn undefined(&mut self, name: &str, span: Span) {
+        let message = match Builtin::from_name(name) {
+            Some(_) => format!("`{}` is a builtin and can only be called", name),
+            None => format!("Undefined Variable : {}", name),
+        };
+        self.errors.push(Error::compile(
+            CompileErrorKind::UndefinedVariable,
+            message,
+            span,
+        ));
+    }
+
+    // a function body sees its parameters and everything in scope around it;
+    // the compiler works out which outer variables it captures
+    fn resolve_function_body(&mut self, params: &mut [Param], func_body: &mut Expr) {
+        self.depth += 1;
+        let outer_scopes = self.scopes.len();
+        for i in 0..params.len() {
+            if let Some(first) = params[..i].iter().find(|p| p.name == params[i].name) {
+                let message = format!("Parameter `{}` is listed twice", first.name);
+                self.error(CompileErrorKind::DuplicateParameter, message, params[i].span);
+            }
+            self.warn_shadowed(&params[i].name, params[i].span);
+            let id = self.declare(&params[i].name, params[i].span, BindingKind::Variable);
+            params[i].binding = Some(id);
+            self.scopes.push(id);
+        }
+
+        self.resolve_expression(func_body);
+
+        for param in params.iter() {
+            if let Some(id) = param.binding {
+                self.warn_unused(id);
+            }
+        }
+        self.scopes.truncate(outer_scopes);
+        self.depth -= 1;
+    }
+
+    // declares `fn (a ... fn (b ... rest))` as one group: a, b, ...
+    fn declare_functions(&mut self, expr: &mut Expr) {
+        if let ExprKind::Function {
+            name,
+            name_span,
+            binding,
+            params,
+            body,
+            ..
+        } = &mut expr.kind
+        {
+            let kind = BindingKind::Function {
+                arity: params.len(),
+            };
+            let id = self.declare(name, *name_span, kind);
+            *binding = Some(id);
+            self.scopes.push(id);
+            self.declare_functions(body);
+        }
+    }
+
+    fn resolve_function_group(&mut self, expr: &mut Expr) {
+        match &mut expr.kind {
+            ExprKind::Function {
+                params,
+                func_body,
+                body,
+                ..
+            } => {
+                self.resolve_function_body(params, func_body);
+                self.resolve_function_group(body);
+            }
+            _ => self.resolve_expression(expr),
+        }
+    }
+
+    // calls to a function by name are checked here; anything else is called
+    // through a closure value and checked when it runs
+    // returns the builtin the callee names, if it is one the program does not
+    // shadow
+    fn resolve_callee(&mut self, callee: &mut Expr, arg_count: usize) -> Option<Builtin> {
+        if let ExprKind::Variable { name, .. } = &callee.kind
+            && self.lookup(name).is_none()
+            && let Some(builtin) = Builtin::from_name(name)
+        {
+            if builtin.arity() != arg_count {
+                let message = format!(
+                    "`{}` takes {} argument(s) but {} were given",
+                    name,
+                    builtin.arity(),
+                    arg_count
+                );
+                self.error(CompileErrorKind::ArityMismatch, message, callee.span);
+            }
+            return Some(builtin);
+        }
+        self.resolve_expression(callee);
+
+        let ExprKind::Variable {
+            name,
+            binding: Some(id),
+        } = &callee.kind
+        else {
+            return None;
+        };
+        if let BindingKind::Function { arity } = self.bindings[id.0].kind
+            && arity != arg_count
+        {
+            let message = format!(
+                "`{}` takes {} argument(s) but {} were given",
+                name, arity, arg_count
+            );
+            self.error(CompileErrorKind::ArityMismatch, message, callee.span);
+        }
+        None
+    }
+
+    pub fn resolve_expression(&mut self, expr: &mut Expr) {
+        let span = expr.span;
+        match &mut expr.kind {
+            ExprKind::Number(_)
+            | ExprKind::Float(_)
+            | ExprKind::Bool(_)
+            | ExprKind::Str(_)
+            | ExprKind::Error => {}
+            ExprKind::Variable { name, binding } => match self.lookup(name) {
+                Some(id) => {
+                    let target = &mut self.bindings[id.0];
+                    target.uses += 1;
+                    target.captured |= target.depth < self.depth;
+                    *binding = Some(id);
+                }
+                None => self.undefined(name, span),
+            },
+            ExprKind::Define {
+                name,
+                name_span,
+                binding,
+                value,
+                body,
+            } => {
+                self.resolve_expression(value);
+
+                self.warn_shadowed(name, *name_span);
+                let id = self.declare(name, *name_span, BindingKind::Variable);
+                *binding = Some(id);
+                self.scopes.push(id);
+                self.resolve_expression(body);
+                self.scopes.pop();
+                self.warn_unused(id);
+            }
+            ExprKind::Set {
+                name,
+                name_span,
+                binding,
+                index,
+                value,
+            } => {
+                if let Some(index) = index {
+                    self.resolve_expression(index);
+                }
+                self.resolve_expression(value);
+                *binding = self.resolve_assignment(name, *name_span);
+            }
+            ExprKind::Function { .. } => {
+                // every function of a directly chained group is in scope in all
+                // of their bodies, so they can call themselves and each other
+                let outer_scopes = self.scopes.len();
+                self.declare_functions(expr);
+                let group = self.scopes[outer_scopes..].to_vec();
+                self.resolve_function_group(expr);
+                self.scopes.truncate(outer_scopes);
+                for id in group {
+                    self.warn_unused(id);
+                }
+            }
+            ExprKind::Call {
+                callee,
+                args,
+                builtin,
+            } => {
+                *builtin = self.resolve_callee(callee, args.len());
+                for arg in args {
+                    self.resolve_expression(arg);
+                }
+            }
+            ExprKind::List(elements) | ExprKind::Block(elements) => {
+                for element in elements {
+                    self.resolve_expression(element);
+                }
+            }
+            ExprKind::Index { target, index } => {
+                self.resolve_expression(target);
+                self.resolve_expression(index);
+            }
+            ExprKind::Pragma { body, .. } => self.resolve_expression(body),
+            ExprKind::Unary { expr, .. } => self.resolve_expression(expr),
+            ExprKind::Binary { left, right, .. } => {
+                self.resolve_expression(left);
+                self.resolve_expression(right);
+            }
+            ExprKind::If {
+                condition,
+                then_branch,
+                else_branch,
+            } => {
+                self.resolve_expression(condition);
+                self.resolve_expression(then_branch);
+                self.resolve_expression(else_branch);
+            }
+            ExprKind::While { condition, body } => {
+                self.resolve_expression(condition);
+                self.resolve_expression(body);
+            }
+        }
+    }
+}
+
+/// Checks every name in `expr`, reporting all undefined variables at once.
+pub fn resolve(mut expr: Expr) -> Result<Resolved, Vec<Error>> {
+    let mut resolver = Resolver::new();
+    resolver.resolve_expression(&mut expr);
+    resolver.check_assignments();
+
+    if !resolver.errors.is_empty() {
+        return Err(resolver.errors);
+    }
+
+    let mut warnings = resolver.warnings;
+    warnings.sort_by_key(|w| w.labels.first().map(|label| label.span.start));
+    Ok(Resolved {
+        expr,
+        bindings: resolver.bindings,
+        warnings,
+    })
+}
@@ -0,0 +1,529 @@
+use std::cmp::Ordering;
+use std::fmt;
+use std::rc::Rc;
+
+#[cfg(feature = "bignum")]
+use num_bigint::{BigInt, Sign};
+#[cfg(feature = "bignum")]
+use num_traits::ToPrimitive;
+
+/// A value on the VM's stack or in a local slot, tagged with its type.
+///
+/// Integers stay integers until an operation mixes them with a float, at
+/// which point the integer is promoted and the result is a float.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Int(i32),
+    // an integer outside the i32 range; smaller results go back to `Int`
+    #[cfg(feature = "bignum")]
+    Big(Rc<BigInt>),
+    Float(f64),
+    // never has a denominator of 1; whole results are `Int`s
+    Rational(Rational),
+    // what comparisons and `and`/`or`/`not` produce; not a number
+    Bool(bool),
+    // immutable, so values share the text instead of copying it
+    Str(Rc<str>),
+    // immutable like strings: `push` and `slice` build new lists
+    List(Rc<[Value]>),
+    // index of a closure on the VM heap
+    Closure(usize),
+}
+
+/// An exact fraction, kept in lowest terms with a positive denominator.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rational {
+    num: i64,
+    den: i64,
+}
+
+impl Rational {
+    /// `num / den` in lowest terms; `None` if `den` is zero or the result
+    /// does not fit.
+    pub fn new(num: i64, den: i64) -> Option<Rational> {
+        if den == 0 {
+            return None;
+        }
+        let divisor = gcd(num, den);
+        let (mut num, mut den) = (num / divisor, den / divisor);
+        if den < 0 {
+            num = num.checked_neg()?;
+            den = den.checked_neg()?;
+        }
+        Some(Rational { num, den })
+    }
+
+    pub fn from_int(n: i32) -> Rational {
+        Rational {
+            num: n as i64,
+            den: 1,
+        }
+    }
+
+    pub fn numerator(self) -> i64 {
+        self.num
+    }
+
+    pub fn denominator(self) -> i64 {
+        self.den
+    }
+
+    pub fn to_f64(self) -> f64 {
+        self.num as f64 / self.den as f64
+    }
+
+    // rounds toward zero, like integer division
+    pub fn trunc(self) -> i64 {
+        self.num / self.den
+    }
+
+    fn floor(self) -> i64 {
+        self.num.div_euclid(self.den)
+    }
+
+    fn add(self, other: Rational) -> Option<Rational> {
+        let num = self
+            .num
+            .checked_mul(other.den)?
+            .checked_add(other.num.checked_mul(self.den)?)?;
+        Rational::new(num, self.den.checked_mul(other.den)?)
+    }
+
+    fn sub(self, other: Rational) -> Option<Rational> {
+        self.add(Rational {
+            num: other.num.checked_neg()?,
+            den: other.den,
+        })
+    }
+
+    fn mul(self, other: Rational) -> Option<Rational> {
+        Rational::new(self.num.checked_mul(other.num)?, self.den.checked_mul(other.den)?)
+    }
+
+    fn div(self, other: Rational) -> Option<Rational> {
+        Rational::new(self.num.checked_mul(other.den)?, self.den.checked_mul(other.num)?)
+    }
+
+    fn pow(self, exponent: i64) -> Option<Rational> {
+        let power = u32::try_from(exponent.unsigned_abs()).ok()?;
+        let result = Rational::new(self.num.checked_pow(power)?, self.den.checked_pow(power)?)?;
+        if exponent < 0 {
+            Rational::new(result.den, result.num)
+        } else {
+            Some(result)
+        }
+    }
+
+    fn cmp(self, other: Rational) -> Ordering {
+        // denominators are positive, so cross-multiplying keeps the order
+        (self.num as i128 * other.den as i128).cmp(&(other.num as i128 * self.den as i128))
+    }
+}
+
+fn gcd(a: i64, b: i64) -> i64 {
+    let (mut a, mut b) = (a.unsigned_abs(), b.unsigned_abs());
+    while b != 0 {
+        (a, b) = (b, a % b);
+    }
+    // only gcd(0, 0) is 0, and a zero denominator never gets here
+    a.max(1) as i64
+}
+
+/// Whether `/` on two integers truncates or gives an exact fraction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DivisionMode {
+    #[default]
+    Integer,
+    Rational,
+}
+
+impl DivisionMode {
+    /// Parses the name used by the `#!division` pragma.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "integer" => Some(DivisionMode::Integer),
+            "rational" => Some(DivisionMode::Rational),
+            _ => None,
+        }
+    }
+}
+
+impl Value {
+    /// Name of the value's type, as used in runtime error messages.
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::Int(_) => "int",
+            #[cfg(feature = "bignum")]
+            Value::Big(_) => "int",
+            Value::Float(_) => "float",
+            Value::Rational(_) => "rational",
+            Value::Bool(_) => "bool",
+            Value::Str(_) => "string",
+            Value::List(_) => "list",
+            Value::Closure(_) => "function",
+        }
+    }
+
+    /// The value as a float, if it is a number.
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            Value::Int(n) => Some(*n as f64),
+            #[cfg(feature = "bignum")]
+            Value::Big(n) => n.to_f64(),
+            Value::Float(x) => Some(*x),
+            Value::Rational(r) => Some(r.to_f64()),
+            Value::Bool(_) | Value::Str(_) | Value::List(_) | Value::Closure(_) => None,
+        }
+    }
+
+    // what `if`, `while`, `and`, `or`, `not` and JMZ treat as false: zero and
+    // `false`. Strings, lists and closures are always true
+    pub fn is_zero(&self) -> bool {
+        match self {
+            Value::Int(n) => *n == 0,
+            // never zero: zero fits in an `Int`
+            #[cfg(feature = "bignum")]
+            Value::Big(_) => false,
+            Value::Float(x) => *x == 0.0,
+            // zero is always an `Int`
+            Value::Rational(_) => false,
+            Value::Bool(b) => !b,
+            Value::Str(_) | Value::List(_) | Value::Closure(_) => false,
+        }
+    }
+
+    /// Numeric ordering across ints and floats. `None` when either side is not
+    /// a number, or a NaN is involved.
+    pub fn compare(&self, other: &Value) -> Option<Ordering> {
+        match (self, other) {
+            (Value::Int(a), Value::Int(b)) => Some(a.cmp(b)),
+            // false before true
+            (Value::Bool(a), Value::Bool(b)) => Some(a.cmp(b)),
+            // by code point, which for ASCII is dictionary order
+            (Value::Str(a), Value::Str(b)) => Some(a.cmp(b)),
+            // element by element, then by length
+            (Value::List(a), Value::List(b)) => {
+                for (x, y) in a.iter().zip(b.iter()) {
+                    match x.compare(y)? {
+                        Ordering::Equal => {}
+                        order => return Some(order),
+                    }
+                }
+                Some(a.len().cmp(&b.len()))
+            }
+            (Value::Rational(_), _) | (_, Value::Rational(_))
+                if self.as_rational().is_some() && other.as_rational().is_some() =>
+            {
+                Some(self.as_rational()?.cmp(other.as_rational()?))
+            }
+            #[cfg(feature = "bignum")]
+            (a, b) if a.is_integer() && b.is_integer() => Some(a.as_big()?.cmp(&b.as_big()?)),
+            (a, b) => a.as_f64()?.partial_cmp(&b.as_f64()?),
+        }
+    }
+
+    /// Wraps an exact fraction, as an `Int` if it is whole. `None` means it
+    /// is whole but does not fit in 32 bits; with the `bignum` feature it
+    /// becomes a `Big` instead.
+    pub fn from_rational(r: Rational) -> Option<Value> {
+        if r.den != 1 {
+            return Some(Value::Rational(r));
+        }
+        match i32::try_from(r.num) {
+            Ok(n) => Some(Value::Int(n)),
+            #[cfg(feature = "bignum")]
+            Err(_) => Some(Value::from_big(BigInt::from(r.num))),
+            #[cfg(not(feature = "bignum"))]
+            Err(_) => None,
+        }
+    }
+
+    /// The value as an exact fraction, if it is an `Int` or a `Rational`.
+    pub fn as_rational(&self) -> Option<Rational> {
+        match self {
+            Value::Int(n) => Some(Rational::from_int(*n)),
+            Value::Rational(r) => Some(*r),
+            _ => None,
+        }
+    }
+
+    pub fn is_integer(&self) -> bool {
+        match self {
+            Value::Int(_) => true,
+            #[cfg(feature = "bignum")]
+            Value::Big(_) => true,
+            _ => false,
+        }
+    }
+}
+
+#[cfg(feature = "bignum")]
+impl Value {
+    /// Wraps an arbitrary-precision result, as an `Int` if it fits.
+    pub fn from_big(n: BigInt) -> Value {
+        match n.to_i32() {
+            Some(small) => Value::Int(small),
+            None => Value::Big(Rc::new(n)),
+        }
+    }
+
+    fn as_big(&self) -> Option<BigInt> {
+        match self {
+            Value::Int(n) => Some(BigInt::from(*n)),
+            Value::Big(n) => Some(BigInt::clone(n)),
+            _ => None,
+        }
+    }
+}
+
+/// What integer arithmetic does when a result does not fit in 32 bits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OverflowMode {
+    /// Stop with an integer overflow error (or promote, with `bignum`).
+    #[default]
+    Checked,
+    /// Wrap around like a fixed-width two's complement counter.
+    Wrapping,
+    /// Clamp to `i32::MIN` / `i32::MAX`.
+    Saturating,
+}
+
+impl OverflowMode {
+    /// Parses the name used by the `#!overflow` pragma.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "checked" => Some(OverflowMode::Checked),
+            "wrapping" => Some(OverflowMode::Wrapping),
+            "saturating" => Some(OverflowMode::Saturating),
+            _ => None,
+        }
+    }
+}
+
+/// The binary arithmetic operators and what they mean for each kind of number.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArithOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem,
+    Pow,
+    FloorDiv,
+}
+
+impl ArithOp {
+    pub fn name(self) -> &'static str {
+        match self {
+            ArithOp::Add => "addition",
+            ArithOp::Sub => "subtraction",
+            ArithOp::Mul => "multiplication",
+            ArithOp::Div => "division",
+            ArithOp::Rem => "modulus",
+            ArithOp::Pow => "power",
+            ArithOp::FloorDiv => "floor division",
+        }
+    }
+
+    // None on overflow or a negative exponent; the divisor has already been
+    // checked for zero
+    fn ints(self, a: i32, b: i32, mode: OverflowMode) -> Option<i32> {
+        match mode {
+            OverflowMode::Checked => match self {
+                ArithOp::Add => a.checked_add(b),
+                ArithOp::Sub => a.checked_sub(b),
+                ArithOp::Mul => a.checked_mul(b),
+                ArithOp::Div => a.checked_div(b),
+                ArithOp::Rem => Some(floor_remainder(a.checked_rem(b)?, b)),
+                ArithOp::Pow => a.checked_pow(u32::try_from(b).ok()?),
+                ArithOp::FloorDiv => Some(floor_quotient(a.checked_div(b)?, a, b)),
+            },
+            OverflowMode::Wrapping => Some(match self {
+                ArithOp::Add => a.wrapping_add(b),
+                ArithOp::Sub => a.wrapping_sub(b),
+                ArithOp::Mul => a.wrapping_mul(b),
+                ArithOp::Div => a.wrapping_div(b),
+                ArithOp::Rem => floor_remainder(a.wrapping_rem(b), b),
+                ArithOp::Pow => a.wrapping_pow(u32::try_from(b).ok()?),
+                ArithOp::FloorDiv => floor_quotient(a.wrapping_div(b), a, b),
+            }),
+            OverflowMode::Saturating => Some(match self {
+                ArithOp::Add => a.saturating_add(b),
+                ArithOp::Sub => a.saturating_sub(b),
+                ArithOp::Mul => a.saturating_mul(b),
+                ArithOp::Div => a.saturating_div(b),
+                // only i32::MIN % -1 overflows, and its true result is 0
+                ArithOp::Rem => floor_remainder(a.wrapping_rem(b), b),
+                ArithOp::Pow => a.saturating_pow(u32::try_from(b).ok()?),
+                ArithOp::FloorDiv => floor_quotient(a.saturating_div(b), a, b),
+            }),
+        }
+    }
+
+    #[cfg(feature = "bignum")]
+    fn bigs(self, a: BigInt, b: BigInt) -> Option<BigInt> {
+        Some(match self {
+            ArithOp::Add => a + b,
+            ArithOp::Sub => a - b,
+            ArithOp::Mul => a * b,
+            ArithOp::Div => a / b,
+            ArithOp::Rem => {
+                let remainder = a % &b;
+                if remainder.sign() != Sign::NoSign && remainder.sign() != b.sign() {
+                    remainder + b
+                } else {
+                    remainder
+                }
+            }
+            // a negative or enormous exponent is still an overflow
+            ArithOp::Pow => a.pow(b.to_u32()?),
+            ArithOp::FloorDiv => {
+                let remainder = &a % &b;
+                let quotient = a / &b;
+                if remainder.sign() != Sign::NoSign && remainder.sign() != b.sign() {
+                    quotient - 1
+                } else {
+                    quotient
+                }
+            }
+        })
+    }
+
+    /// Applies the operator exactly. `None` means a numerator or denominator
+    /// overflowed, or a whole result does not fit in an integer.
+    pub fn rationals(self, a: Rational, b: Rational) -> Option<Value> {
+        let exact = match self {
+            ArithOp::Add => a.add(b)?,
+            ArithOp::Sub => a.sub(b)?,
+            ArithOp::Mul => a.mul(b)?,
+            ArithOp::Div => a.div(b)?,
+            // the remainder left by floor division, as for integers
+            ArithOp::Rem => a.sub(b.mul(Rational::new(a.div(b)?.floor(), 1)?)?)?,
+            ArithOp::Pow if b.den == 1 => a.pow(b.num)?,
+            // a fractional exponent has no exact answer in general
+            ArithOp::Pow => return Some(Value::Float(a.to_f64().powf(b.to_f64()))),
+            ArithOp::FloorDiv => Rational::new(a.div(b)?.floor(), 1)?,
+        };
+        Value::from_rational(exact)
+    }
+
+    pub fn floats(self, a: f64, b: f64) -> f64 {
+        match self {
+            ArithOp::Add => a + b,
+            ArithOp::Sub => a - b,
+            ArithOp::Mul => a * b,
+            ArithOp::Div => a / b,
+            ArithOp::Rem => {
+                let remainder = a % b;
+                if remainder != 0.0 && (remainder < 0.0) != (b < 0.0) {
+                    remainder + b
+                } else {
+                    remainder
+                }
+            }
+            ArithOp::Pow => a.powf(b),
+            ArithOp::FloorDiv => (a / b).floor(),
+        }
+    }
+
+    /// Applies the operator to two integers. `None` means the result overflows
+    /// in checked mode; with the `bignum` feature that only happens for huge
+    /// exponents.
+    pub fn integers(self, a: &Value, b: &Value, mode: OverflowMode) -> Option<Value> {
+        if let (Value::Int(a), Value::Int(b)) = (a, b)
+            && let Some(result) = self.ints(*a, *b, mode)
+        {
+            return Some(Value::Int(result));
+        }
+        #[cfg(feature = "bignum")]
+        return self.bigs(a.as_big()?, b.as_big()?).map(Value::from_big);
+        #[cfg(not(feature = "bignum"))]
+        None
+    }
+}
+
+// turns the truncated quotient of `a / b` into the floored one, so `//` rounds
+// toward negative infinity for ints just as it does for floats and rationals
+fn floor_quotient(truncated: i32, a: i32, b: i32) -> i32 {
+    // an inexact quotient with a negative result is at least i32::MIN + 1
+    if a.wrapping_rem(b) != 0 && (a < 0) != (b < 0) {
+        truncated - 1
+    } else {
+        truncated
+    }
+}
+
+// turns the truncated remainder of `a % b` into the floored one, which takes
+// the sign of `b` so that `a == (a // b) * b + a % b`
+fn floor_remainder(truncated: i32, b: i32) -> i32 {
+    // the signs differ, so this cannot overflow
+    if truncated != 0 && (truncated < 0) != (b < 0) {
+        truncated + b
+    } else {
+        truncated
+    }
+}
+
+/// The binary bitwise operators, defined on 32-bit integers only.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BitOp {
+    And,
+    Or,
+    Xor,
+    Shl,
+    Shr,
+}
+
+impl BitOp {
+    pub fn name(self) -> &'static str {
+        match self {
+            BitOp::And => "bitwise and",
+            BitOp::Or => "bitwise or",
+            BitOp::Xor => "bitwise xor",
+            BitOp::Shl => "left shift",
+            BitOp::Shr => "right shift",
+        }
+    }
+
+    /// `None` means a shift amount outside `0..32`. Bits shifted out on the
+    /// left are lost and `>>` keeps the sign.
+    pub fn ints(self, a: i32, b: i32) -> Option<i32> {
+        match self {
+            BitOp::And => Some(a & b),
+            BitOp::Or => Some(a | b),
+            BitOp::Xor => Some(a ^ b),
+            BitOp::Shl => a.checked_shl(u32::try_from(b).ok()?),
+            BitOp::Shr => a.checked_shr(u32::try_from(b).ok()?),
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Int(n) => write!(f, "{}", n),
+            #[cfg(feature = "bignum")]
+            Value::Big(n) => write!(f, "{}", n),
+            // Debug keeps the `.0` on whole floats so they read as floats
+            Value::Float(x) => write!(f, "{:?}", x),
+            Value::Rational(r) => write!(f, "{}/{}", r.num, r.den),
+            Value::Bool(b) => write!(f, "{}", b),
+            Value::Str(s) => write!(f, "{}", s),
+            Value::List(elements) => {
+                write!(f, "[")?;
+                for (i, element) in elements.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    // quoted, so `["a, b"]` and `["a", "b"]` look different
+                    match element {
+                        Value::Str(s) => write!(f, "{:?}", s)?,
+                        _ => write!(f, "{}", element)?,
+                    }
+                }
+                write!(f, "]")
+            }
+            Value::Closure(_) => write!(f, "<function>"),
+        }
+    }
+}
@@ -1,6 +1,13 @@
+use std::cmp::Ordering;
 use std::fs::File;
 use std::io::Write;
 
+use crate::builtins::Builtin;
+use crate::error::{Error, RuntimeErrorKind};
+use crate::gc::{GcStats, Heap, GC_GROWTH, GC_THRESHOLD};
+use crate::lexer::Span;
+use crate::value::{ArithOp, BitOp, DivisionMode, OverflowMode, Value};
+
 #[repr(i32)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Instruction {
@@ -25,34 +32,271 @@ pub enum Instruction {
     EXP = 18,
     FLRDIV = 19,
     UNK = 20,
+    CALL = 21, // operand: index into `Program::functions`
+    RET = 22,
+    TAILCALL = 23, // like CALL, but replaces the current frame
+    CLOSURE = 24,  // operand: function index; pops its captures, pushes a closure
+    CALLV = 25,    // operand: argument count; calls the closure on top of the stack
+    TAILCALLV = 26,
+    CONST = 27,   // operand: index into `Program::constants`
+    BUILTIN = 28, // operand: `Builtin::index`; replaces its arguments with the result
+    BAND = 29,
+    BOR = 30,
+    BXOR = 31,
+    BNOT = 32,
+    SHL = 33,
+    SHR = 34,
+    LIST = 35,  // operand: element count; pops the elements, pushes the list
+    INDEX = 36, // pops an index and a list or string, pushes the element
+    STORE = 37, // pops a list, a value and an index, pushes the list with that element replaced
+}
+
+/// Where a compiled function starts and how much frame it needs.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FunctionInfo {
+    pub name: String,
+    // code offset of the function's first instruction
+    pub entry: usize,
+    pub arity: usize,
+    // captured values, passed in the slots right after the arguments
+    pub captures: usize,
+    // local slots the body uses, parameters and captures included
+    pub num_locals: usize,
+}
+
+/// Compiled bytecode together with the source span each instruction came from.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Program {
+    pub code: Vec<i32>,
+    // number of local slots the code addresses, so the VM can size its locals up front
+    pub num_locals: usize,
+    // (code offset, span) pairs in emission order, used to locate runtime errors
+    pub spans: Vec<(usize, Span)>,
+    pub functions: Vec<FunctionInfo>,
+    // literals that do not fit in an operand word, pushed by CONST
+    pub constants: Vec<Value>,
+    // set by an `#!overflow` pragma; takes precedence over `VmConfig::overflow`
+    pub overflow: Option<OverflowMode>,
+    // set by a `#!division` pragma; takes precedence over `VmConfig::division`
+    pub division: Option<DivisionMode>,
 }
 
-const STACK_SIZE: usize = 256;
-const NUM_OF_REGISTERS: usize = 16;
-// main registers we can add more later;
-const IP: usize = 14;
-const SP: usize = 15;
+impl Program {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.code.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.code.is_empty()
+    }
+
+    /// Appends an instruction and records `span` as its origin.
+    pub fn emit(&mut self, instr: Instruction, span: Span) -> usize {
+        let pos = self.code.len();
+        self.spans.push((pos, span));
+        self.code.push(instr as i32);
+        pos
+    }
+
+    /// Appends an operand word for the previously emitted instruction.
+    pub fn emit_operand(&mut self, value: i32) {
+        self.code.push(value);
+    }
+
+    /// Adds `value` to the constant pool and returns its index.
+    pub fn add_constant(&mut self, value: Value) -> i32 {
+        self.constants.push(value);
+        self.constants.len() as i32 - 1
+    }
+
+    /// Span of the instruction at `ip`, if it was emitted with one.
+    pub fn span_at(&self, ip: usize) -> Option<Span> {
+        match self.spans.binary_search_by_key(&ip, |&(pos, _)| pos) {
+            Ok(i) => Some(self.spans[i].1),
+            Err(0) => None,
+            Err(i) => Some(self.spans[i - 1].1),
+        }
+    }
+}
+
+/// Default limit on nested function calls.
+pub const MAX_CALL_DEPTH: usize = 1024;
+/// Default operand stack size, in values: room for a few pending operands per
+/// call at the default call depth.
+pub const STACK_SIZE: usize = 4 * MAX_CALL_DEPTH;
+/// Largest number of local variable slots a program may use.
+pub const MAX_LOCALS: usize = 1 << 16;
+// machine registers only; variables live in the `locals` area
+const NUM_OF_REGISTERS: usize = 2;
+const IP: usize = 0;
+const SP: usize = 1;
+
+/// A function together with the values it captured when it was created.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Closure {
+    pub function: usize,
+    pub captured: Vec<Value>,
+}
+
+// one active function call; the top level runs in the bottom frame
+#[derive(Debug, Clone, Copy)]
+struct Frame {
+    // ip of the CALL operand, so execution resumes right after the call
+    return_ip: i32,
+    // first slot of this frame in `locals`; SET/GET operands are relative to it
+    base: usize,
+    size: usize,
+}
+
+/// Limits the VM enforces while running a program.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VmConfig {
+    pub stack_size: usize,
+    // calls that may be active at once; the top level does not count
+    pub max_call_depth: usize,
+    // for programs without an `#!overflow` pragma
+    pub overflow: OverflowMode,
+    // for programs without a `#!division` pragma
+    pub division: DivisionMode,
+    // heap bytes allocated before the first garbage collection
+    pub gc_threshold: usize,
+    // after a collection, the heap may grow to this many times what survived
+    // before the next one
+    pub gc_growth: usize,
+}
+
+impl Default for VmConfig {
+    fn default() -> Self {
+        Self {
+            stack_size: STACK_SIZE,
+            max_call_depth: MAX_CALL_DEPTH,
+            overflow: OverflowMode::Checked,
+            division: DivisionMode::Integer,
+            gc_threshold: GC_THRESHOLD,
+            gc_growth: GC_GROWTH,
+        }
+    }
+}
 
 pub struct VM {
     running: bool,
-    stack: [i32; STACK_SIZE],
+    config: VmConfig,
+    // the config's modes, unless the running program sets its own by pragma
+    overflow: OverflowMode,
+    division: DivisionMode,
+    stack: Vec<Value>,
     registers: [i32; NUM_OF_REGISTERS],
-    error: bool,
+    // variable slots addressed by SET/GET, grown on demand
+    locals: Vec<Value>,
+    frames: Vec<Frame>,
+    // closures live here; a closure value on the stack is an index into it
+    heap: Heap,
+    error: Option<Error>,
+}
+
+impl Default for VM {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl VM {
     pub fn new() -> Self {
+        Self::with_config(VmConfig::default())
+    }
+
+    pub fn with_config(config: VmConfig) -> Self {
         let mut vm = VM {
             running: false,
-            stack: [0; STACK_SIZE],
+            config,
+            overflow: config.overflow,
+            division: config.division,
+            stack: vec![Value::Int(0); config.stack_size],
             registers: [0; NUM_OF_REGISTERS],
-            error: false,
+            locals: Vec::new(),
+            frames: Vec::new(),
+            heap: Heap::new(config.gc_threshold, config.gc_growth),
+            error: None,
         };
         vm.registers[SP] = -1;
         vm.registers[IP] = 0;
         vm
     }
 
+    /// Runs `program` to completion. The VM can be inspected afterwards, for
+    /// example with `gc_stats`, and reused to run another program.
+    pub fn run(&mut self, program: &Program, log_file: &mut File) -> Result<Option<Value>, Error> {
+        // nothing from an earlier run stays reachable; the heap keeps its
+        // statistics and frees that run's closures at the next collection
+        self.registers[SP] = -1;
+        self.registers[IP] = 0;
+        self.frames.clear();
+        self.locals.clear();
+        self.error = None;
+        self.overflow = program.overflow.unwrap_or(self.config.overflow);
+        self.division = program.division.unwrap_or(self.config.division);
+        self.locals.resize(program.num_locals, Value::Int(0));
+        self.frames.push(Frame {
+            return_ip: 0,
+            base: 0,
+            size: program.num_locals,
+        });
+        self.running = true;
+
+        while self.running {
+            let ip = self.ip();
+            if ip < 0 || (ip as usize) >= program.len() {
+                writeln!(
+                    log_file,
+                    "Error: Program terminated without HLT or invalid IP"
+                )
+                .ok();
+                self.fail(
+                    RuntimeErrorKind::InvalidJump,
+                    "Program terminated without HLT or invalid IP",
+                );
+                break;
+            }
+
+            let instr = self.fetch(&program.code);
+            self.eval(instr, program);
+
+            writeln!(
+                log_file,
+                "IP: {}, SP: {}, Instr: {}, Stack: {:?}",
+                self.ip(),
+                self.sp(),
+                instr,
+                &self.stack[0..=self.sp().max(0) as usize]
+            )
+            .ok();
+
+            if let Some(error) = self.error.take() {
+                return Err(error.with_span(program.span_at(ip as usize)));
+            }
+
+            *self.ip_mut() += 1;
+        }
+
+        if let Some(error) = self.error.take() {
+            Err(error)
+        } else if self.sp() >= 0 {
+            Ok(Some(self.stack[self.sp() as usize].clone()))
+        } else {
+            Ok(None)
+        }
+    }
+
+    // records a runtime error; returns false so helpers can `return self.fail(..)`
+    fn fail(&mut self, kind: RuntimeErrorKind, message: &str) -> bool {
+        self.error = Some(Error::runtime(kind, message));
+        false
+    }
+
     fn sp(&self) -> i32 {
         self.registers[SP]
     }
@@ -73,189 +317,559 @@ impl VM {
         program[self.ip() as usize]
     }
 
-    fn push(&mut self, value: i32) -> bool {
-        if self.sp() < STACK_SIZE as i32 - 1 {
+    fn push(&mut self, value: Value) -> bool {
+        if self.sp() + 1 < self.stack.len() as i32 {
             *self.sp_mut() += 1;
-            self.stack[self.sp() as usize] = value;
+            let sp = self.sp() as usize;
+            self.stack[sp] = value;
             true
         } else {
-            eprint!("STACK OVERFLOW");
-            self.error = true;
-            false
+            self.fail(RuntimeErrorKind::StackOverflow, "Stack overflow")
         }
     }
 
-    fn pop(&mut self) -> Option<i32> {
+    fn pop(&mut self) -> Option<Value> {
         if self.sp() >= 0 {
-            let value = self.stack[self.sp() as usize];
+            let value = self.stack[self.sp() as usize].clone();
             *self.sp_mut() -= 1;
             Some(value)
         } else {
-            eprintln!("STACK UNDERFLOW");
-            self.error = true;
+            self.fail(RuntimeErrorKind::StackUnderflow, "Stack underflow");
             None
         }
     }
 
-    fn add(&mut self) -> bool {
-        if let (Some(a), Some(b)) = (self.pop(), self.pop()) {
-            match b.checked_add(a) {
-                Some(res) => {
-                    self.push(res);
-                    true
-                }
-                None => {
-                    eprintln!("Error : Integer overflow in addition");
-                    self.error = true;
-                    false
-                }
+    // pops the right operand, then the left one
+    fn pop_operands(&mut self) -> Option<(Value, Value)> {
+        let right = self.pop()?;
+        let left = self.pop()?;
+        Some((left, right))
+    }
+
+    fn type_mismatch(&mut self, what: &str, left: &Value, right: &Value) -> bool {
+        self.fail(
+            RuntimeErrorKind::TypeMismatch,
+            &format!(
+                "Type mismatch in {}: {} and {}",
+                what,
+                left.type_name(),
+                right.type_name()
+            ),
+        )
+    }
+
+    fn make_list(&mut self, count: i32) -> bool {
+        let Ok(count) = usize::try_from(count) else {
+            return self.fail(RuntimeErrorKind::InvalidInstruction, "Negative list length");
+        };
+        let mut elements = Vec::with_capacity(count);
+        for _ in 0..count {
+            match self.pop() {
+                Some(element) => elements.push(element),
+                None => return false,
             }
-        } else {
-            self.error = true;
-            false
         }
+        elements.reverse();
+        self.push(Value::List(elements.into()))
     }
 
-    fn sub(&mut self) -> bool {
-        if let (Some(a), Some(b)) = (self.pop(), self.pop()) {
-            match b.checked_sub(a) {
-                Some(res) => {
-                    self.push(res);
-                    true
+    fn index(&mut self) -> bool {
+        let Some((target, index)) = self.pop_operands() else {
+            return false;
+        };
+        let len = match &target {
+            Value::List(elements) => elements.len(),
+            Value::Str(s) => s.chars().count(),
+            _ => return self.type_mismatch("indexing", &target, &index),
+        };
+        let Value::Int(i) = index else {
+            return self.type_mismatch("indexing", &target, &index);
+        };
+        let Some(at) = usize::try_from(i).ok().filter(|&at| at < len) else {
+            let message = format!("Index {} is out of bounds for a {} of length {}", i, target.type_name(), len);
+            return self.fail(RuntimeErrorKind::IndexOutOfBounds, &message);
+        };
+        let element = match &target {
+            Value::List(elements) => elements[at].clone(),
+            Value::Str(s) => Value::Str(s.chars().nth(at).unwrap().to_string().into()),
+            _ => unreachable!("checked above"),
+        };
+        self.push(element)
+    }
+
+    // lists are shared, so the element is replaced in a copy
+    fn store(&mut self) -> bool {
+        let Some(target) = self.pop() else {
+            return false;
+        };
+        let Some((index, value)) = self.pop_operands() else {
+            return false;
+        };
+        let Value::List(elements) = &target else {
+            return self.type_mismatch("element assignment", &target, &index);
+        };
+        let Value::Int(i) = index else {
+            return self.type_mismatch("element assignment", &target, &index);
+        };
+        let Some(at) = usize::try_from(i).ok().filter(|&at| at < elements.len()) else {
+            let message = format!("Index {} is out of bounds for a list of length {}", i, elements.len());
+            return self.fail(RuntimeErrorKind::IndexOutOfBounds, &message);
+        };
+        let mut elements = elements.to_vec();
+        elements[at] = value;
+        self.push(Value::List(elements.into()))
+    }
+
+    // `+` on two strings joins them; any other operator or pairing is left
+    // to `arithmetic`, which reports the mismatch
+    fn concat(&mut self) -> bool {
+        if self.sp() >= 1
+            && let (Value::Str(a), Value::Str(b)) =
+                (&self.stack[self.sp() as usize - 1], &self.stack[self.sp() as usize])
+        {
+            let joined = Value::Str(format!("{}{}", a, b).into());
+            self.pop_operands();
+            return self.push(joined);
+        }
+        self.arithmetic(ArithOp::Add)
+    }
+
+    // integers stay integers (except `/` in rational mode) and mixing one with
+    // a rational stays exact; as soon as either side is a float both are
+    // promoted. Anything that is not a number is a type mismatch
+    fn arithmetic(&mut self, op: ArithOp) -> bool {
+        let Some((left, right)) = self.pop_operands() else {
+            return false;
+        };
+        let exact_division = op == ArithOp::Div && self.division == DivisionMode::Rational;
+        let result = if left.is_integer() && right.is_integer() && !exact_division {
+            match op.integers(&left, &right, self.overflow) {
+                Some(result) => result,
+                None => {
+                    return self.fail(
+                        RuntimeErrorKind::IntegerOverflow,
+                        &format!("Integer overflow in {}", op.name()),
+                    );
                 }
+            }
+        } else if let (Some(a), Some(b)) = (left.as_rational(), right.as_rational()) {
+            match op.rationals(a, b) {
+                Some(result) => result,
                 None => {
-                    eprintln!("Error: Integer overflow in subtraction");
-                    self.error = true;
-                    false
+                    return self.fail(
+                        RuntimeErrorKind::IntegerOverflow,
+                        &format!("Rational overflow in {}", op.name()),
+                    );
                 }
             }
+        } else if let (Some(a), Some(b)) = (left.as_f64(), right.as_f64()) {
+            Value::Float(op.floats(a, b))
         } else {
-            self.error = true;
-            false
+            return self.type_mismatch(op.name(), &left, &right);
+        };
+        self.push(result)
+    }
+
+    // only 32-bit integers have bits to operate on
+    fn bitwise(&mut self, op: BitOp) -> bool {
+        let Some((left, right)) = self.pop_operands() else {
+            return false;
+        };
+        let (Value::Int(a), Value::Int(b)) = (&left, &right) else {
+            if left.is_integer() && right.is_integer() {
+                // one of them is a bignum
+                let message = format!("Integer overflow in {}: operands must fit in 32 bits", op.name());
+                return self.fail(RuntimeErrorKind::IntegerOverflow, &message);
+            }
+            return self.type_mismatch(op.name(), &left, &right);
+        };
+        match op.ints(*a, *b) {
+            Some(result) => self.push(Value::Int(result)),
+            None => self.fail(
+                RuntimeErrorKind::ShiftOutOfRange,
+                &format!("Shift amount {} is out of range: expected 0 to 31", b),
+            ),
         }
     }
 
+    fn bit_not(&mut self) -> bool {
+        let Some(value) = self.pop() else {
+            return false;
+        };
+        let Value::Int(n) = value else {
+            let message = format!("Type mismatch in bitwise not: {}", value.type_name());
+            return self.fail(RuntimeErrorKind::TypeMismatch, &message);
+        };
+        self.push(Value::Int(!n))
+    }
+
+    fn add(&mut self) -> bool {
+        self.concat()
+    }
+
+    fn sub(&mut self) -> bool {
+        self.arithmetic(ArithOp::Sub)
+    }
+
     fn mult(&mut self) -> bool {
-        if let (Some(a), Some(b)) = (self.pop(), self.pop()) {
-            match b.checked_mul(a) {
-                Some(result) => {
-                    self.push(result);
-                    true
-                }
-                None => {
-                    eprintln!("Error: Integer overflow in multiplication");
-                    self.error = true;
-                    false
-                }
-            }
-        } else {
-            self.error = true;
-            false
+        self.arithmetic(ArithOp::Mul)
+    }
+
+    // the divisor is checked first so floats raise the same error as ints
+    // instead of producing infinities
+    fn divisor_is_zero(&mut self, message: &str) -> bool {
+        let zero = self.sp() >= 0 && self.stack[self.sp() as usize].is_zero();
+        if zero {
+            self.fail(RuntimeErrorKind::DivisionByZero, message);
         }
+        zero
     }
 
     fn divide(&mut self) -> bool {
-        if let (Some(a), Some(b)) = (self.pop(), self.pop()) {
-            if a == 0 {
-                eprintln!("Error: Cannot Divide By Zero");
-                self.push(b);
-                self.push(a);
-                self.error = true;
+        if self.divisor_is_zero("Cannot Divide By Zero") {
+            return false;
+        }
+        self.arithmetic(ArithOp::Div)
+    }
+
+    // slot `slot` of the current frame as an index into `locals`
+    fn local_index(&self, slot: i32) -> Option<usize> {
+        let slot = usize::try_from(slot).ok().filter(|&slot| slot < MAX_LOCALS)?;
+        let base = self.frames.last().map_or(0, |frame| frame.base);
+        Some(base + slot)
+    }
+
+    fn call(&mut self, index: i32, program: &Program) -> bool {
+        let Some(function) = usize::try_from(index).ok().and_then(|i| program.functions.get(i)) else {
+            return self.fail(RuntimeErrorKind::InvalidCall, &format!("Unknown function: {}", index));
+        };
+        // the bottom frame is the top level, not a call
+        if self.frames.len() > self.config.max_call_depth {
+            return self.fail(
+                RuntimeErrorKind::StackDepthExceeded,
+                &format!(
+                    "Stack depth exceeded: more than {} nested calls",
+                    self.config.max_call_depth
+                ),
+            );
+        }
+        let base = self
+            .frames
+            .last()
+            .map_or(0, |frame| frame.base + frame.size);
+        let incoming = function.arity + function.captures;
+        self.locals.resize(base + function.num_locals.max(incoming), Value::Int(0));
+
+        // arguments were pushed left to right, captures after them, so the
+        // last capture is on top
+        for i in (0..incoming).rev() {
+            let Some(arg) = self.pop() else {
+                return false;
+            };
+            self.locals[base + i] = arg;
+        }
+
+        self.frames.push(Frame {
+            return_ip: self.ip(),
+            base,
+            size: function.num_locals,
+        });
+        *self.ip_mut() = function.entry as i32 - 1;
+        true
+    }
+
+    // a call whose result the caller returns unchanged: the callee takes over
+    // the caller's frame, so tail recursion runs in constant space
+    fn tail_call(&mut self, index: i32, program: &Program) -> bool {
+        if self.frames.len() <= 1 {
+            return self.call(index, program);
+        }
+        let Some(function) = usize::try_from(index).ok().and_then(|i| program.functions.get(i)) else {
+            return self.fail(RuntimeErrorKind::InvalidCall, &format!("Unknown function: {}", index));
+        };
+
+        // the arguments may have been computed from the slots they replace
+        let mut args = vec![Value::Int(0); function.arity + function.captures];
+        for arg in args.iter_mut().rev() {
+            let Some(value) = self.pop() else {
+                return false;
+            };
+            *arg = value;
+        }
+
+        let frame = self.frames.last_mut().unwrap();
+        frame.size = function.num_locals;
+        let base = frame.base;
+        self.locals.truncate(base);
+        let incoming = args.len();
+        self.locals.extend(args);
+        self.locals.resize(base + function.num_locals.max(incoming), Value::Int(0));
+
+        *self.ip_mut() = function.entry as i32 - 1;
+        true
+    }
+
+    fn make_closure(&mut self, index: i32, program: &Program) -> bool {
+        let Some(function) = usize::try_from(index).ok().filter(|&i| i < program.functions.len()) else {
+            return self.fail(RuntimeErrorKind::InvalidCall, &format!("Unknown function: {}", index));
+        };
+        // before the captures are popped, while they are still roots
+        if self.heap.wants_collection() {
+            self.collect_garbage();
+        }
+        let mut captured = vec![Value::Int(0); program.functions[function].captures];
+        for value in captured.iter_mut().rev() {
+            let Some(popped) = self.pop() else {
                 return false;
+            };
+            *value = popped;
+        }
+        let index = self.heap.allocate(Closure { function, captured });
+        self.push(Value::Closure(index))
+    }
+
+    /// Frees the closures the program can no longer reach. The roots are the
+    /// live part of the stack and every frame's locals; the registers only
+    /// hold addresses, never values.
+    pub fn collect_garbage(&mut self) {
+        let live_stack = &self.stack[..(self.sp() + 1) as usize];
+        self.heap.collect(live_stack.iter().chain(&self.locals));
+    }
+
+    pub fn gc_stats(&self) -> GcStats {
+        self.heap.stats()
+    }
+
+    // pops the closure a CALLV/TAILCALLV applies and puts its captures where
+    // a direct call would have them; returns the function to jump to
+    fn unpack_closure(&mut self, argc: i32, program: &Program) -> Option<i32> {
+        let handle = self.pop()?;
+        let Value::Closure(i) = handle else {
+            let message = format!("Type mismatch: cannot call {} {}", handle.type_name(), handle);
+            self.fail(RuntimeErrorKind::TypeMismatch, &message);
+            return None;
+        };
+        let Some(closure) = self.heap.get(i) else {
+            self.fail(RuntimeErrorKind::InvalidCall, &format!("Dangling closure: {}", i));
+            return None;
+        };
+        let closure = closure.clone();
+        let function = &program.functions[closure.function];
+        if function.arity as i32 != argc {
+            let message = format!(
+                "`{}` takes {} argument(s) but {} were given",
+                function.name, function.arity, argc
+            );
+            self.fail(RuntimeErrorKind::ArityMismatch, &message);
+            return None;
+        }
+        for value in closure.captured {
+            if !self.push(value) {
+                return None;
+            }
+        }
+        Some(closure.function as i32)
+    }
+
+    // builtins run in place: no frame, just arguments in and a result out
+    fn call_builtin(&mut self, index: i32) -> bool {
+        let Some(builtin) = Builtin::from_index(index) else {
+            return self.fail(RuntimeErrorKind::InvalidCall, &format!("Unknown builtin: {}", index));
+        };
+        let mut args = Vec::with_capacity(builtin.arity());
+        for _ in 0..builtin.arity() {
+            match self.pop() {
+                Some(arg) => args.push(arg),
+                None => return false,
             }
-            match b.checked_div(a) {
-                Some(result) => {
-                    self.push(result);
-                    true
+        }
+        args.reverse();
+
+        let result = match (builtin, &args[..]) {
+            (Builtin::Int, [Value::Float(x)]) => {
+                let truncated = x.trunc();
+                if !(i32::MIN as f64..=i32::MAX as f64).contains(&truncated) {
+                    return self.fail(
+                        RuntimeErrorKind::IntegerOverflow,
+                        &format!("Integer overflow converting {:?} to int", x),
+                    );
                 }
-                None => {
-                    eprintln!("Error: Integer overflow in Division");
-                    self.error = true;
-                    false
+                Value::Int(truncated as i32)
+            }
+            (Builtin::Int, [Value::Rational(r)]) => match i32::try_from(r.trunc()) {
+                Ok(n) => Value::Int(n),
+                Err(_) => {
+                    return self.fail(
+                        RuntimeErrorKind::IntegerOverflow,
+                        &format!("Integer overflow converting {} to int", args[0]),
+                    );
                 }
+            },
+            (Builtin::Int, [n]) if n.is_integer() => n.clone(),
+            (Builtin::Float, [n]) if n.as_f64().is_some() => Value::Float(n.as_f64().unwrap()),
+            (Builtin::Str, [value]) => Value::Str(value.to_string().into()),
+            (Builtin::Len, [Value::Str(s)]) => Value::Int(s.chars().count() as i32),
+            (Builtin::Len, [Value::List(elements)]) => Value::Int(elements.len() as i32),
+            (Builtin::Push, [Value::List(elements), value]) => {
+                let mut elements = elements.to_vec();
+                elements.push(value.clone());
+                Value::List(elements.into())
             }
-        } else {
-            self.error = true;
-            false
+            (Builtin::Slice, [Value::List(elements), Value::Int(start), Value::Int(end)]) => {
+                let range = usize::try_from(*start).ok().zip(usize::try_from(*end).ok());
+                match range.filter(|&(start, end)| start <= end && end <= elements.len()) {
+                    Some((start, end)) => Value::List(elements[start..end].into()),
+                    None => {
+                        let message = format!(
+                            "Slice {}..{} is out of bounds for a list of length {}",
+                            start,
+                            end,
+                            elements.len()
+                        );
+                        return self.fail(RuntimeErrorKind::IndexOutOfBounds, &message);
+                    }
+                }
+            }
+            (Builtin::Substr, [Value::Str(s), Value::Int(start), Value::Int(count)]) => {
+                match substring(s, *start, *count) {
+                    Some(sub) => Value::Str(sub.into()),
+                    None => {
+                        let message = format!(
+                            "Substring of {} character(s) at {} is out of bounds for a string of length {}",
+                            count,
+                            start,
+                            s.chars().count()
+                        );
+                        return self.fail(RuntimeErrorKind::IndexOutOfBounds, &message);
+                    }
+                }
+            }
+            (Builtin::Compare, [a, b]) if a.compare(b).is_some() => match a.compare(b).unwrap() {
+                Ordering::Less => Value::Int(-1),
+                Ordering::Equal => Value::Int(0),
+                Ordering::Greater => Value::Int(1),
+            },
+            _ => {
+                let got: Vec<&str> = args.iter().map(Value::type_name).collect();
+                let message = format!(
+                    "Type mismatch: `{}` expects {}, got {}",
+                    builtin.name(),
+                    builtin.expects(),
+                    got.join(", ")
+                );
+                return self.fail(RuntimeErrorKind::TypeMismatch, &message);
+            }
+        };
+        self.push(result)
+    }
+
+    // the return value is already on top of the stack; only the frame goes
+    fn ret(&mut self) -> bool {
+        if self.frames.len() <= 1 {
+            return self.fail(RuntimeErrorKind::InvalidCall, "Return outside of a function");
         }
+        let frame = self.frames.pop().unwrap();
+        self.locals.truncate(frame.base);
+        *self.ip_mut() = frame.return_ip;
+        true
     }
 
-    fn compare(&mut self, op: fn(i32, i32) -> bool) -> bool {
-        if let (Some(b), Some(a)) = (self.pop(), self.pop()) {
-            let result = if op(a, b) { 1 } else { 0 };
-            self.push(result);
-            true
+    // `==` and `!=` work on any two values; only ordering can mismatch
+    fn equality(&mut self, negate: bool) -> bool {
+        let Some((a, b)) = self.pop_operands() else {
+            return false;
+        };
+        let equal = self.values_equal(&a, &b);
+        self.push(Value::Bool(equal != negate))
+    }
+
+    // values of different types are never equal. Naming a function makes a
+    // new closure each time, so two closures are the same function when they
+    // run the same code over equal captures
+    fn values_equal(&self, a: &Value, b: &Value) -> bool {
+        match (a, b) {
+            (Value::List(x), Value::List(y)) => {
+                x.len() == y.len() && x.iter().zip(y.iter()).all(|(x, y)| self.values_equal(x, y))
+            }
+            (Value::Closure(x), Value::Closure(y)) => {
+                x == y
+                    || match (self.heap.get(*x), self.heap.get(*y)) {
+                        (Some(f), Some(g)) => {
+                            f.function == g.function
+                                && f.captured.len() == g.captured.len()
+                                && f.captured.iter().zip(g.captured.iter()).all(|(x, y)| self.values_equal(x, y))
+                        }
+                        _ => false,
+                    }
+            }
+            _ => a.compare(b) == Some(Ordering::Equal),
+        }
+    }
+
+    fn compare(&mut self, op: fn(Option<Ordering>) -> bool) -> bool {
+        if let Some((a, b)) = self.pop_operands() {
+            if a.compare(&b).is_none() && (a.as_f64().is_none() || b.as_f64().is_none()) {
+                return self.type_mismatch("comparison", &a, &b);
+            }
+            self.push(Value::Bool(op(a.compare(&b))))
         } else {
-            self.error = true;
             false
         }
     }
-    
+
+    // the result of an integer power would be a fraction, which no overflow
+    // mode can represent
     fn expn(&mut self) -> bool {
-        if let (Some(a), Some(b)) = (self.pop(), self.pop()) {
-            match b.checked_pow(a as u32) {
-                Some(res) => {
-                    self.push(res);
-                    true
-                }
-                None => {
-                    eprintln!("Error Integer overflow in power");
-                    self.error = true;
-                    false
-                }
-            }
-        }else {
-            self.error = true;
-            false
+        let sp = self.sp();
+        if sp >= 1
+            && self.stack[sp as usize - 1].is_integer()
+            && self.stack[sp as usize].is_integer()
+            && self.stack[sp as usize].compare(&Value::Int(0)) == Some(Ordering::Less)
+        {
+            let message = format!(
+                "Negative exponent {} in integer power: use a float or rational base",
+                self.stack[sp as usize]
+            );
+            return self.fail(RuntimeErrorKind::NegativeExponent, &message);
         }
+        self.arithmetic(ArithOp::Pow)
     }
 
     fn modulus(&mut self) -> bool {
-        if let (Some(a), Some(b)) = (self.pop(), self.pop()) {
-            match b.checked_rem(a) {
-                Some(res) => {
-                    self.push(res);
-                    true
-                }
-                None => {
-                    eprintln!("Error Integer overflow in Modulus");
-                    self.error = true;
-                    false
-                }
-            }
-        }else {
-            self.error = true;
-            false
+        if self.divisor_is_zero("Modulus by zero") {
+            return false;
         }
+        self.arithmetic(ArithOp::Rem)
     }
 
-    fn floor_div (&mut self) -> bool {
-        if let (Some(a), Some(b)) = (self.pop(), self.pop()) {
-            match b.checked_div(a) {
-                Some(res) => {
-                    self.push(res.abs());
-                    true
-                }
-                None => {
-                    eprintln!("Error Integer overflow in Modulus");
-                    self.error = true;
-                    false
-                }
-            }
-        }else {
-            self.error = true;
-            false
+    fn floor_div(&mut self) -> bool {
+        if self.divisor_is_zero("Floor division by zero") {
+            return false;
         }
+        self.arithmetic(ArithOp::FloorDiv)
     }
 
-    fn eval(&mut self, instr: i32, program: &[i32]) {
+    fn eval(&mut self, instr: i32, program: &Program) {
+        let code = &program.code;
         match instr {
             x if x == Instruction::HLT as i32 => {
                 self.running = false;
             }
             x if x == Instruction::PSH as i32 => {
                 *self.ip_mut() += 1;
-                let value = program[self.ip() as usize];
+                let value = Value::Int(code[self.ip() as usize]);
+                if !self.push(value) {
+                    self.running = false;
+                }
+            }
+            x if x == Instruction::CONST as i32 => {
+                *self.ip_mut() += 1;
+                let index = code[self.ip() as usize];
+                let Some(value) = usize::try_from(index).ok().and_then(|i| program.constants.get(i)).cloned() else {
+                    self.fail(RuntimeErrorKind::InvalidInstruction, "Invalid constant index");
+                    self.running = false;
+                    return;
+                };
                 if !self.push(value) {
                     self.running = false;
                 }
@@ -300,72 +914,100 @@ impl VM {
                     self.running = false;
                 }
             }
+            x if x == Instruction::BAND as i32 => {
+                if !self.bitwise(BitOp::And) {
+                    self.running = false;
+                }
+            }
+            x if x == Instruction::BOR as i32 => {
+                if !self.bitwise(BitOp::Or) {
+                    self.running = false;
+                }
+            }
+            x if x == Instruction::BXOR as i32 => {
+                if !self.bitwise(BitOp::Xor) {
+                    self.running = false;
+                }
+            }
+            x if x == Instruction::SHL as i32 => {
+                if !self.bitwise(BitOp::Shl) {
+                    self.running = false;
+                }
+            }
+            x if x == Instruction::SHR as i32 => {
+                if !self.bitwise(BitOp::Shr) {
+                    self.running = false;
+                }
+            }
+            x if x == Instruction::BNOT as i32 => {
+                if !self.bit_not() {
+                    self.running = false;
+                }
+            }
             x if x == Instruction::SET as i32 => {
                 *self.ip_mut() += 1;
-                let reg_id = program[self.ip() as usize] as usize;
-                if reg_id >= NUM_OF_REGISTERS - 2 {
-                    eprintln!("Error: Invalid register id");
-                    self.error = true;
+                let Some(slot) = self.local_index(code[self.ip() as usize]) else {
+                    self.fail(RuntimeErrorKind::InvalidLocal, "Invalid local slot");
                     self.running = false;
                     return;
+                };
+                if slot >= self.locals.len() {
+                    self.locals.resize(slot + 1, Value::Int(0));
                 }
                 if let Some(val) = self.pop() {
-                    self.registers[reg_id] = val;
+                    self.locals[slot] = val;
                 }else {
                     self.running = false;
                 }
             }
             x if x == Instruction::GET as i32 => {
                 *self.ip_mut() += 1;
-                let reg_id = program[self.ip() as usize] as usize;
+                let slot = code[self.ip() as usize];
 
-                if reg_id >= NUM_OF_REGISTERS - 2 {
-                    eprintln!("Error: Invalid register id");
-                    self.error = true;
+                let Some(value) = self.local_index(slot).and_then(|slot| self.locals.get(slot)).cloned() else {
+                    self.fail(RuntimeErrorKind::InvalidLocal, "Invalid local slot");
                     self.running = false;
                     return;
-                }
-
-                let value = self.registers[reg_id];
+                };
                 if !self.push(value){
                     self.running = false;
                 }
             }
             x if x == Instruction::EQ as i32 => {
-                if !self.compare(|a, b| a == b) {
+                if !self.equality(false) {
                     self.running = false;
                 }
             }
             x if x == Instruction::NEQ as i32 => {
-                if !self.compare(|a, b| a != b) {
+                if !self.equality(true) {
                     self.running = false;
                 }
             }
             x if x == Instruction::LSS as i32 => {
-                if !self.compare(|a, b| a < b) {
+                if !self.compare(|o| o == Some(Ordering::Less)) {
                     self.running = false;
                 }
             }
             x if x == Instruction::GTR as i32 => {
-                if !self.compare(|a, b| a > b) {
+                if !self.compare(|o| o == Some(Ordering::Greater)) {
                     self.running = false;
                 }
             }
             x if x == Instruction::LEQ as i32 => {
-                if !self.compare(|a, b| a <= b) {
+                if !self.compare(|o| matches!(o, Some(Ordering::Less | Ordering::Equal))) {
                     self.running = false;
                 }
             }
             x if x == Instruction::GEQ as i32 => {
-                if !self.compare(|a, b| a >= b) {
+                if !self.compare(|o| matches!(o, Some(Ordering::Greater | Ordering::Equal))) {
                     self.running = false;
                 }
             }
             x if x == Instruction::JMZ as i32 => {
                 *self.ip_mut() += 1;
-                let target = program[self.ip() as usize];
+                let target = code[self.ip() as usize];
                 if let Some(cond) = self.pop() {
-                    if cond == 0 {
+                    if cond.is_zero() {
                         *self.ip_mut() = target -1;
                     }
                 }else {
@@ -375,56 +1017,102 @@ impl VM {
 
             x if x ==Instruction::JMP as i32 => {
                 *self.ip_mut() += 1; 
-                let target = program[self.ip() as usize];
+                let target = code[self.ip() as usize];
                 *self.ip_mut() = target -1; 
             }
 
+            x if x == Instruction::CALL as i32 => {
+                *self.ip_mut() += 1;
+                let index = code[self.ip() as usize];
+                if !self.call(index, program) {
+                    self.running = false;
+                }
+            }
+            x if x == Instruction::TAILCALL as i32 => {
+                *self.ip_mut() += 1;
+                let index = code[self.ip() as usize];
+                if !self.tail_call(index, program) {
+                    self.running = false;
+                }
+            }
+            x if x == Instruction::CLOSURE as i32 => {
+                *self.ip_mut() += 1;
+                let index = code[self.ip() as usize];
+                if !self.make_closure(index, program) {
+                    self.running = false;
+                }
+            }
+            x if x == Instruction::CALLV as i32 || x == Instruction::TAILCALLV as i32 => {
+                *self.ip_mut() += 1;
+                let argc = code[self.ip() as usize];
+                let called = match self.unpack_closure(argc, program) {
+                    Some(index) if x == Instruction::TAILCALLV as i32 => self.tail_call(index, program),
+                    Some(index) => self.call(index, program),
+                    None => false,
+                };
+                if !called {
+                    self.running = false;
+                }
+            }
+            x if x == Instruction::BUILTIN as i32 => {
+                *self.ip_mut() += 1;
+                let index = code[self.ip() as usize];
+                if !self.call_builtin(index) {
+                    self.running = false;
+                }
+            }
+            x if x == Instruction::LIST as i32 => {
+                *self.ip_mut() += 1;
+                let count = code[self.ip() as usize];
+                if !self.make_list(count) {
+                    self.running = false;
+                }
+            }
+            x if x == Instruction::INDEX as i32 => {
+                if !self.index() {
+                    self.running = false;
+                }
+            }
+            x if x == Instruction::STORE as i32 => {
+                if !self.store() {
+                    self.running = false;
+                }
+            }
+            x if x == Instruction::RET as i32 => {
+                if !self.ret() {
+                    self.running = false;
+                }
+            }
+
             _ => {
-                println!("Unknown instruction: {}", instr);
-                self.error = true;
+                self.fail(
+                    RuntimeErrorKind::InvalidInstruction,
+                    &format!("Unknown instruction: {}", instr),
+                );
                 self.running = false;
             }
         }
     }
 }
 
-pub fn run_program(program: Vec<i32>, log_file: &mut File) -> Result<Option<i32>, ()> {
-    let mut vm = VM::new();
-    vm.running = true;
-
-    while vm.running {
-        let ip = vm.ip();
-        if ip < 0 || (ip as usize) >= program.len() {
-            writeln!(
-                log_file,
-                "Error: Program terminated without HLT or invalid IP"
-            )
-            .ok();
-            vm.error = true;
-            break;
-        }
-
-        let instr = vm.fetch(&program);
-        vm.eval(instr, &program);
-
-        writeln!(
-            log_file,
-            "IP: {}, SP: {}, Instr: {}, Stack: {:?}",
-            vm.ip(),
-            vm.sp(),
-            instr,
-            &vm.stack[0..=vm.sp().max(0) as usize]
-        )
-        .ok();
-
-        *vm.ip_mut() += 1;
+// `count` characters starting at character `start`, if all of them exist
+fn substring(s: &str, start: i32, count: i32) -> Option<String> {
+    let (start, count) = (usize::try_from(start).ok()?, usize::try_from(count).ok()?);
+    let len = s.chars().count();
+    if start.checked_add(count)? > len {
+        return None;
     }
+    Some(s.chars().skip(start).take(count).collect())
+}
 
-    if vm.error {
-        Err(())
-    } else if vm.sp() >= 0 {
-        Ok(Some(vm.stack[vm.sp() as usize]))
-    } else {
-        Ok(None)
-    }
+pub fn run_program(program: &Program, log_file: &mut File) -> Result<Option<Value>, Error> {
+    run_program_with_config(program, VmConfig::default(), log_file)
+}
+
+pub fn run_program_with_config(
+    program: &Program,
+    config: VmConfig,
+    log_file: &mut File,
+) -> Result<Option<Value>, Error> {
+    VM::with_config(config).run(program, log_file)
 }
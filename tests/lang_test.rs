@@ -2,9 +2,28 @@ use std::fs;
 use std::fs::File;
 
 fn run_expression(input: &str) -> Result<i32, String> {
+    use expression_solver::value::Value;
+
+    match evaluate(input)? {
+        Value::Int(n) => Ok(n),
+        other => Err(format!("Expected an integer result, got {}", other)),
+    }
+}
+
+fn run_bool(input: &str) -> Result<bool, String> {
+    use expression_solver::value::Value;
+
+    match evaluate(input)? {
+        Value::Bool(b) => Ok(b),
+        other => Err(format!("Expected a boolean result, got {}", other)),
+    }
+}
+
+fn evaluate(input: &str) -> Result<expression_solver::value::Value, String> {
     use expression_solver::lexer::Lexer;
     use expression_solver::parser::Parser;
     use expression_solver::compiler::compile;
+    use expression_solver::resolver::resolve;
     use expression_solver::vm::run_program;
     
     let mut lexer = Lexer::new(input);
@@ -13,13 +32,14 @@ fn run_expression(input: &str) -> Result<i32, String> {
     let mut parser = Parser::new(tokens);
     let ast = parser.parse().map_err(|e| format!("Parser error: {}", e))?;
     
-    let bytecode = compile(&ast);
+    let resolved = resolve(ast).map_err(|e| format!("Resolve error: {}", e[0]))?;
+    let program = compile(&resolved).map_err(|e| format!("Compile error: {}", e))?;
     
     let mut log_file = File::create("/tmp/test_log.log")
         .map_err(|e| format!("Failed to create log file: {}", e))?;
     
-    let result = run_program(bytecode, &mut log_file)
-        .map_err(|_| "VM error".to_string())?;
+    let result = run_program(&program, &mut log_file)
+        .map_err(|e| format!("VM error: {}", e))?;
     
     result.ok_or_else(|| "No result on stack".to_string())
 }
@@ -49,19 +69,48 @@ fn test_exponentiation() {
 
 #[test]
 fn test_floor_division() {
+    use expression_solver::value::Value;
+
     assert_eq!(run_expression("7 // 2").unwrap(), 3);
     assert_eq!(run_expression("10 // 3").unwrap(), 3);
     assert_eq!(run_expression("20 // 6").unwrap(), 3);
+
+    // rounds toward negative infinity, whatever kind of number it divides
+    assert_eq!(run_expression("-7 // 2").unwrap(), -4);
+    assert_eq!(run_expression("7 // -2").unwrap(), -4);
+    assert_eq!(run_expression("-7 // -2").unwrap(), 3);
+    assert_eq!(run_expression("-6 // 2").unwrap(), -3);
+    assert_eq!(evaluate("-7.0 // 2").unwrap(), Value::Float(-4.0));
+    assert_eq!(evaluate("7 // -2.0").unwrap(), Value::Float(-4.0));
+    assert_eq!(run_expression("#!division rational\n(-7 / 1) // 2").unwrap(), -4);
+    assert_eq!(run_expression("#!overflow wrapping\n(-2147483647 - 1) // -1").unwrap(), i32::MIN);
+
+    // `%` is floored too, taking the sign of the divisor, so that
+    // a == (a // b) * b + a % b for every pair of operands
+    assert_eq!(run_expression("-7 % 2").unwrap(), 1);
+    assert_eq!(run_expression("7 % -2").unwrap(), -1);
+    assert_eq!(evaluate("-7.5 % 2").unwrap(), Value::Float(0.5));
+    assert_eq!(evaluate("#!division rational\n(-7 / 2) % 1").unwrap().to_string(), "1/2");
+    for a in ["-7", "7", "-6", "-7.5", "(-7 / 3)"] {
+        for b in ["2", "-2", "3", "-3", "2.5"] {
+            let identity = format!("#!division rational\ndefine (a {} define (b {} a == (a // b) * b + a % b))", a, b);
+            assert!(run_bool(&identity).unwrap(), "{} and {}", a, b);
+        }
+    }
+    for mode in ["wrapping", "saturating"] {
+        let source = format!("#!overflow {}\n(-2147483647 - 1) % -1 == 0 && -7 % 2 == 1", mode);
+        assert!(run_bool(&source).unwrap(), "{}", mode);
+    }
 }
 
 #[test]
 fn test_comparisons() {
-    assert_eq!(run_expression("5 == 5").unwrap(), 1);
-    assert_eq!(run_expression("5 == 3").unwrap(), 0);
-    assert_eq!(run_expression("5 > 3").unwrap(), 1);
-    assert_eq!(run_expression("3 < 5").unwrap(), 1);
-    assert_eq!(run_expression("5 >= 5").unwrap(), 1);
-    assert_eq!(run_expression("5 <= 3").unwrap(), 0);
+    assert!(run_bool("5 == 5").unwrap());
+    assert!(!run_bool("5 == 3").unwrap());
+    assert!(run_bool("5 > 3").unwrap());
+    assert!(run_bool("3 < 5").unwrap());
+    assert!(run_bool("5 >= 5").unwrap());
+    assert!(!run_bool("5 <= 3").unwrap());
 }
 
 #[test]
@@ -79,7 +128,7 @@ fn test_if_statement() {
 
 #[test]
 fn test_while_loop() {
-    let expr = "define (x 5 define (sum 0 define (dummy while (x > 0 define (sum (sum + x) define (x (x - 1) sum))) sum)))";
+    let expr = "define (x 5 define (sum 0 define (dummy while (x > 0 define (_ set (sum (sum + x)) set (x (x - 1)))) sum)))";
     assert_eq!(run_expression(expr).unwrap(), 15); // 5+4+3+2+1
 }
 
@@ -112,3 +161,1161 @@ fn test_fibonacci_program() {
     let result = run_expression(&input).unwrap();
     assert_eq!(result, 13); // 7th fibonacci: 1,1,2,3,5,8,13
 }
+
+#[test]
+fn test_token_and_expr_spans() {
+    use expression_solver::lexer::{Lexer, TokenKind};
+    use expression_solver::parser::{ExprKind, Parser};
+
+    let source = "define (x 5\n    x + 42\n)";
+    let tokens = Lexer::new(source).tokenize().unwrap();
+
+    let answer = tokens.iter().find(|t| t.kind == TokenKind::Number(42)).unwrap();
+    assert_eq!((answer.span.line, answer.span.column), (2, 9));
+    assert_eq!(&source[answer.span.start..answer.span.end], "42");
+
+    let ast = Parser::new(tokens).parse().unwrap();
+    assert_eq!(ast.span.start, 0);
+    assert_eq!(ast.span.end, source.len());
+    match ast.kind {
+        ExprKind::Define { body, .. } => {
+            assert_eq!(&source[body.span.start..body.span.end], "x + 42");
+            assert_eq!((body.span.line, body.span.column), (2, 5));
+        }
+        other => panic!("expected define, got {:?}", other),
+    }
+}
+
+fn pipeline_error(input: &str) -> expression_solver::error::Error {
+    use expression_solver::compiler::compile;
+    use expression_solver::lexer::Lexer;
+    use expression_solver::parser::Parser;
+    use expression_solver::resolver::resolve;
+    use expression_solver::vm::run_program;
+
+    let tokens = match Lexer::new(input).tokenize() {
+        Ok(tokens) => tokens,
+        Err(e) => return e,
+    };
+    let ast = match Parser::new(tokens).parse() {
+        Ok(ast) => ast,
+        Err(e) => return e,
+    };
+    let resolved = match resolve(ast) {
+        Ok(resolved) => resolved,
+        Err(mut errors) => return errors.remove(0),
+    };
+    let program = match compile(&resolved) {
+        Ok(program) => program,
+        Err(e) => return e,
+    };
+    let mut log_file = File::create("/tmp/test_error_log.log").unwrap();
+    run_program(&program, &mut log_file).expect_err("expected the program to fail")
+}
+
+// the kind enum of one pipeline stage, so `error_parts` can hand back a
+// typed kind whichever stage a test expects to fail
+trait StageKind: Sized {
+    fn split(
+        error: expression_solver::error::Error,
+    ) -> Option<(Self, String, Option<expression_solver::lexer::Span>)>;
+}
+
+macro_rules! stage_kind {
+    ($kind:ident, $variant:ident) => {
+        impl StageKind for expression_solver::error::$kind {
+            fn split(
+                error: expression_solver::error::Error,
+            ) -> Option<(Self, String, Option<expression_solver::lexer::Span>)> {
+                match error {
+                    expression_solver::error::Error::$variant { kind, message, span } => {
+                        Some((kind, message, span))
+                    }
+                    _ => None,
+                }
+            }
+        }
+    };
+}
+
+stage_kind!(LexErrorKind, Lex);
+stage_kind!(ParseErrorKind, Parse);
+stage_kind!(CompileErrorKind, Compile);
+stage_kind!(RuntimeErrorKind, Runtime);
+
+// the kind, message and span of `error`, which has to come from the stage `K`
+// belongs to
+fn split_error<K: StageKind>(
+    error: expression_solver::error::Error,
+) -> (K, String, Option<expression_solver::lexer::Span>) {
+    let shown = format!("{:?}", error);
+    K::split(error).unwrap_or_else(|| {
+        panic!("expected a {} error, got {}", std::any::type_name::<K>(), shown)
+    })
+}
+
+// the kind, message and span of the error `input` fails with
+fn error_parts<K: StageKind>(input: &str) -> (K, String, Option<expression_solver::lexer::Span>) {
+    split_error(pipeline_error(input))
+}
+
+fn compile_source(input: &str) -> expression_solver::vm::Program {
+    use expression_solver::compiler::compile;
+    use expression_solver::lexer::Lexer;
+    use expression_solver::parser::Parser;
+    use expression_solver::resolver::resolve;
+
+    let tokens = Lexer::new(input).tokenize().unwrap();
+    compile(&resolve(Parser::new(tokens).parse().unwrap()).unwrap()).unwrap()
+}
+
+fn run_with_config(
+    input: &str,
+    config: expression_solver::vm::VmConfig,
+) -> Result<Option<expression_solver::value::Value>, expression_solver::error::Error> {
+    use expression_solver::vm::run_program_with_config;
+
+    let mut log_file = File::create("/tmp/test_config_log.log").unwrap();
+    run_program_with_config(&compile_source(input), config, &mut log_file)
+}
+
+#[test]
+fn test_structured_errors() {
+    use expression_solver::error::{
+        CompileErrorKind, Error, LexErrorKind, ParseErrorKind, RuntimeErrorKind,
+    };
+
+    assert!(matches!(
+        pipeline_error("5 $ 3"),
+        Error::Lex { kind: LexErrorKind::InvalidCharacter, .. }
+    ));
+    assert!(matches!(
+        pipeline_error("define (x 5 x"),
+        Error::Parse { kind: ParseErrorKind::Unclosed { .. }, .. }
+    ));
+    assert!(matches!(
+        pipeline_error("define (x"),
+        Error::Parse { kind: ParseErrorKind::UnexpectedEof, .. }
+    ));
+    assert!(matches!(
+        pipeline_error("define (x 5 y)"),
+        Error::Compile { kind: CompileErrorKind::UndefinedVariable, .. }
+    ));
+
+    let source = "define (x 0\n  10 / x\n)";
+    let error = pipeline_error(source);
+    assert!(matches!(
+        error,
+        Error::Runtime { kind: RuntimeErrorKind::DivisionByZero, .. }
+    ));
+    let span = error.span().expect("runtime errors are located");
+    assert_eq!(&source[span.start..span.end], "10 / x");
+    assert_eq!(span.line, 2);
+
+    // with the `bignum` feature this promotes instead of failing
+    #[cfg(not(feature = "bignum"))]
+    assert!(matches!(
+        pipeline_error("2 ** 40"),
+        Error::Runtime { kind: RuntimeErrorKind::IntegerOverflow, .. }
+    ));
+}
+
+#[test]
+fn test_diagnostic_points_at_unclosed_define() {
+    use expression_solver::diagnostics::Diagnostic;
+
+    let source = "define (a 1\n    define (b 2\n        a + b\n    )\n";
+    let error = pipeline_error(source);
+    let rendered = Diagnostic::from(&error).render(source, "broken.expr");
+
+    let expected = "\
+error: Expected ')' to close define expression
+ --> broken.expr:4:6
+  |
+1 | define (a 1
+  | ------ unclosed expression starts here
+...
+4 |     )
+  |      ^ expected ')' here
+";
+    assert_eq!(rendered, expected);
+}
+
+#[test]
+fn test_parser_recovers_and_reports_every_error() {
+    use expression_solver::error::{Error, ParseErrorKind};
+    use expression_solver::lexer::Lexer;
+    use expression_solver::parser::{ExprKind, Parser};
+
+    let source = "define (a (1 + )\n    define (b 2 *\n        if (a < b a)\n    )\n)";
+    let tokens = Lexer::new(source).tokenize().unwrap();
+    let (ast, errors) = Parser::new(tokens).parse_recovering();
+
+    let lines: Vec<usize> = errors.iter().map(|e| e.span().unwrap().line).collect();
+    assert_eq!(lines, vec![1, 3, 3]);
+    assert!(errors
+        .iter()
+        .all(|e| matches!(e, Error::Parse { kind: ParseErrorKind::UnexpectedToken, .. })));
+
+    // the tree is still there, with placeholders where the errors were
+    match ast.kind {
+        ExprKind::Define { name, value, body, .. } => {
+            assert_eq!(name, "a");
+            assert!(matches!(value.kind, ExprKind::Error));
+            assert!(matches!(body.kind, ExprKind::Define { .. }));
+        }
+        other => panic!("expected define, got {:?}", other),
+    }
+
+    let tokens = Lexer::new(&fs::read_to_string("tests/sample3.expr").unwrap())
+        .tokenize()
+        .unwrap();
+    let (_, errors) = Parser::new(tokens).parse_recovering();
+    assert!(errors.is_empty());
+}
+
+#[test]
+fn test_resolver_reports_every_undefined_variable() {
+    use expression_solver::error::{CompileErrorKind, Error};
+    use expression_solver::lexer::Lexer;
+    use expression_solver::parser::Parser;
+    use expression_solver::resolver::resolve;
+
+    let source = "define (x 1\n    y + (define (z 2 x)) + w\n)";
+    let tokens = Lexer::new(source).tokenize().unwrap();
+    let ast = Parser::new(tokens).parse().unwrap();
+    let errors = resolve(ast).expect_err("y and w are undefined");
+
+    let names: Vec<&str> = errors
+        .iter()
+        .map(|e| {
+            assert!(matches!(e, Error::Compile { kind: CompileErrorKind::UndefinedVariable, .. }));
+            let span = e.span().unwrap();
+            &source[span.start..span.end]
+        })
+        .collect();
+    assert_eq!(names, vec!["y", "w"]);
+}
+
+#[test]
+fn test_resolver_warnings() {
+    use expression_solver::lexer::Lexer;
+    use expression_solver::parser::Parser;
+    use expression_solver::resolver::resolve;
+
+    let source = "define (unused 1 define (x 2 define (y 3 define (_quiet 0 set (x y)))))";
+    let tokens = Lexer::new(source).tokenize().unwrap();
+    let resolved = resolve(Parser::new(tokens).parse().unwrap()).unwrap();
+
+    let messages: Vec<&str> = resolved.warnings.iter().map(|w| w.message.as_str()).collect();
+    // assigning `x` is not reading it
+    assert_eq!(messages, vec!["unused variable `unused`", "unused variable `x`"]);
+    assert_eq!(run_expression(source).unwrap(), 3);
+
+    // a define or a parameter that reuses a name in scope points at both
+    let shadowing = |source: &str| {
+        let tokens = Lexer::new(source).tokenize().unwrap();
+        let resolved = resolve(Parser::new(tokens).parse().unwrap()).unwrap();
+        let warning = resolved
+            .warnings
+            .iter()
+            .find(|w| w.message.contains("shadows"))
+            .expect("expected a shadowing warning")
+            .clone();
+        let at = |i: usize| warning.labels[i].span.start;
+        (warning.message, at(0), at(1))
+    };
+    let (message, inner, outer) = shadowing("define (x 1 define (x 2 x))");
+    assert_eq!(message, "`x` shadows an existing binding");
+    assert_eq!((inner, outer), (20, 8));
+    let (message, inner, outer) = shadowing("define (n 1 fn (f (n) n f(n)))");
+    assert_eq!(message, "`n` shadows an existing binding");
+    assert_eq!((inner, outer), (19, 8));
+}
+
+#[test]
+fn test_hundreds_of_variables() {
+    // 300 nested defines, all live at once: far more than the old 14 registers
+    let count = 300;
+    let mut program = String::new();
+    for i in 0..count {
+        program.push_str(&format!("define (v{} {} ", i, i));
+    }
+    let sum: Vec<String> = (0..count).map(|i| format!("v{}", i)).collect();
+    program.push_str(&sum.join(" + "));
+    program.push_str(&")".repeat(count));
+
+    // the parser and compiler recurse once per nesting level, so give this
+    // the stack a main thread would have rather than the smaller test default
+    let result = std::thread::Builder::new()
+        .stack_size(8 << 20)
+        .spawn(move || run_expression(&program))
+        .unwrap()
+        .join()
+        .unwrap();
+    assert_eq!(result.unwrap(), (0..count as i32).sum::<i32>());
+}
+
+#[test]
+fn test_sibling_scopes_share_registers() {
+    let locals = |source: &str| compile_source(source).num_locals;
+
+    // each define closes before the next opens, so one slot is enough
+    let siblings = "(define (a 1 a)) + (define (b 2 b)) + (define (c 3 define (d 4 c + d)))";
+    assert_eq!(locals(siblings), 2);
+    assert_eq!(run_expression(siblings).unwrap(), 10);
+
+    // `sq` is dead once the loop ends, so `_r` (bound to the loop's result)
+    // takes its slot; the loop itself still sees distinct slots
+    let looped = "define (i 0 define (total 0 define (_r while (i < 4 \
+                  define (sq (i * i) define (_ set (total (total + sq)) set (i (i + 1))))) total)))";
+    assert_eq!(locals(looped), 4);
+    assert_eq!(run_expression(looped).unwrap(), 14);
+}
+
+#[test]
+fn test_functions() {
+    assert_eq!(run_expression("fn (square (x) x * x square(7))").unwrap(), 49);
+    assert_eq!(
+        run_expression("fn (sub (a b) a - b sub(10, 3) + sub(1, 2))").unwrap(),
+        6
+    );
+    // a function body can call functions defined around it, and its locals
+    // do not disturb the caller's
+    let nested = "define (x 5 fn (double (n) n * 2 \
+                  fn (quad (n) define (t double(n) double(t)) quad(x) + x)))";
+    assert_eq!(run_expression(nested).unwrap(), 25);
+    assert_eq!(run_expression("fn (zero () 0 zero() + 1)").unwrap(), 1);
+    // a '(' only calls a name or a call, even when it touches a literal
+    assert_eq!(run_expression("if (1 (2)(3))").unwrap(), 2);
+    assert_eq!(run_expression("define (a 5(3))").unwrap(), 3);
+}
+
+#[test]
+fn test_function_errors() {
+    use expression_solver::error::{CompileErrorKind, ParseErrorKind};
+
+    let compile_kind = |source: &str| error_parts::<CompileErrorKind>(source).0;
+
+    assert_eq!(
+        compile_kind("fn (f (a b) a + b f(1))"),
+        CompileErrorKind::ArityMismatch
+    );
+    // only a name, a call or an index can be called, so a literal never is
+    let (kind, _, _) = error_parts::<ParseErrorKind>("5(1)");
+    assert_eq!(kind, ParseErrorKind::TrailingTokens);
+    assert_eq!(compile_kind("fn (f (a a) a f(1, 2))"), CompileErrorKind::DuplicateParameter);
+}
+
+#[test]
+fn test_recursion() {
+    let factorial = "fn (fact (n) if (n <= 1 1 n * fact(n - 1)) fact(10))";
+    assert_eq!(run_expression(factorial).unwrap(), 3628800);
+
+    let ackermann = "fn (ack (m n) \
+                     if (m == 0 n + 1 if (n == 0 ack(m - 1, 1) ack(m - 1, ack(m, n - 1)))) \
+                     ack(2, 3))";
+    assert_eq!(run_expression(ackermann).unwrap(), 9);
+
+    // functions chained directly after one another can call each other
+    let mutual = "fn (is_even (n) if (n == 0 1 is_odd(n - 1)) \
+                  fn (is_odd (n) if (n == 0 0 is_even(n - 1)) \
+                  is_even(10) * 10 + is_odd(7)))";
+    assert_eq!(run_expression(mutual).unwrap(), 11);
+}
+
+#[test]
+fn test_call_depth_limit() {
+    use expression_solver::error::RuntimeErrorKind;
+    use expression_solver::value::Value;
+    use expression_solver::vm::VmConfig;
+
+    // never reaches a base case, and the `+ 1` keeps the call out of tail position
+    let (kind, _, _) = error_parts::<RuntimeErrorKind>("fn (forever (n) forever(n + 1) + 1 forever(0))");
+    assert_eq!(kind, RuntimeErrorKind::StackDepthExceeded);
+
+    let source = "fn (count (n) if (n == 0 0 1 + count(n - 1)) count(20))";
+    let config = VmConfig {
+        max_call_depth: 10,
+        ..VmConfig::default()
+    };
+    let error = run_with_config(source, config).unwrap_err();
+    let (kind, message, _) = split_error::<RuntimeErrorKind>(error);
+    assert_eq!(kind, RuntimeErrorKind::StackDepthExceeded);
+    assert!(message.contains("10"), "{}", message);
+
+    let config = VmConfig {
+        max_call_depth: 21,
+        ..VmConfig::default()
+    };
+    assert_eq!(run_with_config(source, config).unwrap(), Some(Value::Int(20)));
+
+    // each pending `n +` holds a value on the operand stack, which the default
+    // stack has room for up to the default call depth
+    let sum = |n: i32| format!("fn (sum (n) if (n == 0 0 n + sum(n - 1)) sum({}))", n);
+    assert_eq!(run_expression(&sum(400)).unwrap(), 80200);
+    let (kind, _, _) = error_parts::<RuntimeErrorKind>(&sum(1100));
+    assert_eq!(kind, RuntimeErrorKind::StackDepthExceeded);
+
+    // a smaller operand stack runs out before the call limit is reached,
+    // which is a plain stack overflow
+    let config = VmConfig {
+        stack_size: 64,
+        ..VmConfig::default()
+    };
+    let (kind, _, _) = split_error::<RuntimeErrorKind>(run_with_config(&sum(400), config).unwrap_err());
+    assert_eq!(kind, RuntimeErrorKind::StackOverflow);
+    // so is a big list built one call deep
+    let big_list = format!("fn (f () [{}] f())", vec!["1"; 100].join(", "));
+    let (kind, _, _) = split_error::<RuntimeErrorKind>(run_with_config(&big_list, config).unwrap_err());
+    assert_eq!(kind, RuntimeErrorKind::StackOverflow);
+}
+
+#[test]
+fn test_tail_calls_reuse_the_frame() {
+    use expression_solver::vm::Instruction;
+
+    // far deeper than the default call-depth limit
+    let sum = "fn (sum (n acc) if (n == 0 acc define (next (n - 1) sum(next, acc + n))) \
+               sum(5000, 0))";
+    assert_eq!(run_expression(sum).unwrap(), 12502500);
+
+    let mutual = "fn (is_even (n) if (n == 0 1 is_odd(n - 1)) \
+                  fn (is_odd (n) if (n == 0 0 is_even(n - 1)) \
+                  is_even(3001)))";
+    assert_eq!(run_expression(mutual).unwrap(), 0);
+
+    let instructions = |source: &str| {
+        let program = compile_source(source);
+        // every instruction (but no operand) has an entry in the span table
+        let opcodes: Vec<i32> = program.spans.iter().map(|&(pos, _)| program.code[pos]).collect();
+        let count = |instr: Instruction| opcodes.iter().filter(|&&op| op == instr as i32).count();
+        (count(Instruction::CALL), count(Instruction::TAILCALL))
+    };
+    // the top-level call and the one feeding `n *` are not in tail position
+    assert_eq!(
+        instructions("fn (fact (n) if (n <= 1 1 n * fact(n - 1)) fact(5))"),
+        (2, 0)
+    );
+    assert_eq!(instructions(sum), (1, 1));
+}
+
+#[test]
+fn test_closures() {
+    let adder = "fn (make_adder (n) fn (add (x) x + n add) \
+                 define (add5 make_adder(5) define (add7 make_adder(7) add5(10) * 100 + add7(1))))";
+    assert_eq!(run_expression(adder).unwrap(), 1508);
+    assert_eq!(run_expression("fn (make_adder (n) fn (add (x) x + n add) make_adder(2)(3))").unwrap(), 5);
+
+    // `inner` only reaches `base` through `outer`, which has to capture it too
+    let nested = "define (base 100 fn (outer (a) fn (inner (b) a + b + base inner(1)) outer(20)))";
+    assert_eq!(run_expression(nested).unwrap(), 121);
+
+    // captures are copies taken when the closure is made
+    let snapshot = "define (x 1 fn (get () x define (g get define (x 50 g() + x))))";
+    assert_eq!(run_expression(snapshot).unwrap(), 51);
+
+    let twice = "fn (twice (f v) f(f(v)) fn (inc (v) v + 1 twice(inc, 5)))";
+    assert_eq!(run_expression(twice).unwrap(), 7);
+}
+
+#[test]
+fn test_closure_call_errors() {
+    use expression_solver::error::RuntimeErrorKind;
+
+    let runtime_kind = |source: &str| error_parts::<RuntimeErrorKind>(source).0;
+
+    assert_eq!(runtime_kind("define (y 2 y(1))"), RuntimeErrorKind::TypeMismatch);
+    assert_eq!(
+        runtime_kind("fn (f (a) a define (g f g(1, 2)))"),
+        RuntimeErrorKind::ArityMismatch
+    );
+}
+
+#[test]
+fn test_float_literals() {
+    use expression_solver::lexer::{Lexer, TokenKind};
+
+    let kinds = |source: &str| -> Vec<TokenKind> {
+        Lexer::new(source).tokenize().unwrap().into_iter().map(|t| t.kind).collect()
+    };
+    assert_eq!(kinds("6.02"), vec![TokenKind::Float(6.02)]);
+    assert_eq!(kinds("1e-9"), vec![TokenKind::Float(1e-9)]);
+    assert_eq!(kinds("2.5E+3"), vec![TokenKind::Float(2500.0)]);
+    assert_eq!(kinds("7"), vec![TokenKind::Number(7)]);
+
+    let error = Lexer::new("1e").tokenize().unwrap_err();
+    assert!(matches!(
+        error,
+        expression_solver::error::Error::Lex {
+            kind: expression_solver::error::LexErrorKind::InvalidNumber,
+            ..
+        }
+    ));
+
+    // too large for an f64, rather than silently infinite
+    let source = "1 + 1.5e400";
+    match Lexer::new(source).tokenize().unwrap_err() {
+        expression_solver::error::Error::Lex {
+            kind: expression_solver::error::LexErrorKind::InvalidNumber,
+            message,
+            span,
+        } => {
+            let span = span.unwrap();
+            assert_eq!(&source[span.start..span.end], "1.5e400");
+            assert!(message.contains("too large"), "{}", message);
+        }
+        other => panic!("expected an invalid number, got {:?}", other),
+    }
+    assert_eq!(kinds("1e-400"), vec![TokenKind::Float(0.0)]);
+}
+
+#[test]
+fn test_float_arithmetic() {
+    use expression_solver::value::Value;
+
+    assert_eq!(evaluate("1.5 + 2.25").unwrap(), Value::Float(3.75));
+    // mixing an int with a float promotes the int
+    assert_eq!(evaluate("2 * 0.5").unwrap(), Value::Float(1.0));
+    assert_eq!(evaluate("7.0 // 2").unwrap(), Value::Float(3.0));
+    assert_eq!(evaluate("2 ** 0.5").unwrap(), Value::Float(2f64.sqrt()));
+    assert_eq!(evaluate("-1.5").unwrap(), Value::Float(-1.5));
+    // two ints stay an int
+    assert_eq!(evaluate("7 / 2").unwrap(), Value::Int(3));
+
+    assert!(run_bool("0.1 + 0.2 > 0.3").unwrap());
+    assert!(run_bool("2 == 2.0").unwrap());
+    assert_eq!(run_expression("if (0.0 1 2)").unwrap(), 2);
+
+    let kinetic = "define (m 2.0 define (v 3 0.5 * m * v ** 2))";
+    assert_eq!(evaluate(kinetic).unwrap(), Value::Float(9.0));
+    assert_eq!(evaluate("1.0 / 4").unwrap().to_string(), "0.25");
+    assert_eq!(evaluate("2.0 * 3").unwrap().to_string(), "6.0");
+
+    assert!(evaluate("1.5 / 0").unwrap_err().contains("Divide By Zero"));
+}
+
+#[test]
+fn test_type_mismatch() {
+    use expression_solver::error::RuntimeErrorKind;
+    use expression_solver::value::Value;
+
+    let mismatch = |source: &str| {
+        let (kind, message, _) = error_parts::<RuntimeErrorKind>(source);
+        assert_eq!(kind, RuntimeErrorKind::TypeMismatch, "{}", message);
+        message
+    };
+
+    let f = "fn (f (x) x ";
+    assert_eq!(mismatch(&format!("{}f + 1)", f)), "Type mismatch in addition: function and int");
+    assert_eq!(mismatch(&format!("{}2.5 * f)", f)), "Type mismatch in multiplication: float and function");
+    assert_eq!(mismatch(&format!("{}f < 3)", f)), "Type mismatch in comparison: function and int");
+    for op in ["-", "/", "**", "%", "//", "<=", ">="] {
+        mismatch(&format!("{}f {} 1)", f, op));
+    }
+
+    // equality is defined between any two values: different types are unequal
+    for (source, expected) in [
+        (r#""a" == 1"#, false),
+        (r#""a" != 1"#, true),
+        ("true == 1", false),
+        ("[1] == [1.0]", true),
+        (r#"[1, "a"] == [1, 2]"#, false),
+        ("fn (f (x) x f == f)", true),
+        ("fn (f (x) x define (g f g == f))", true),
+        ("fn (f (x) x fn (g (x) x f == g))", false),
+        ("fn (adder (n) fn (add (x) x + n add) adder(1) == adder(1))", true),
+        ("fn (adder (n) fn (add (x) x + n add) adder(1) != adder(2))", true),
+    ] {
+        assert_eq!(evaluate(source).unwrap(), Value::Bool(expected), "{}", source);
+    }
+
+    // closures are values of their own type, not integers in disguise
+    assert!(matches!(evaluate("fn (f (x) x f)").unwrap(), Value::Closure(_)));
+    assert_eq!(run_expression("fn (f (x) x if (f 1 2))").unwrap(), 1);
+}
+
+#[test]
+#[cfg(feature = "bignum")]
+fn test_bignum_integers() {
+    use expression_solver::value::Value;
+
+    let factorial = "fn (fact (n) if (n <= 1 1 n * fact(n - 1)) fact(25))";
+    assert_eq!(evaluate(factorial).unwrap().to_string(), "15511210043330985984000000");
+
+    let fib = "fn (fib (n a b) if (n == 0 a fib(n - 1, b, a + b)) fib(100, 0, 1))";
+    assert_eq!(evaluate(fib).unwrap().to_string(), "354224848179261915075");
+
+    assert_eq!(evaluate("2 ** 100").unwrap().to_string(), "1267650600228229401496703205376");
+    assert_eq!(evaluate("0 - 2147483647 - 2").unwrap().to_string(), "-2147483649");
+
+    // results that fit in 32 bits go back to plain integers
+    assert_eq!(evaluate("(2 ** 40) / (2 ** 38)").unwrap(), Value::Int(4));
+    assert_eq!(evaluate("(2 ** 40) - (2 ** 40)").unwrap(), Value::Int(0));
+    assert!(run_bool("2 ** 64 > 2 ** 63").unwrap());
+    assert_eq!(run_expression("(2 ** 40) % 7").unwrap(), 2);
+    assert_eq!(evaluate("2 ** 40 * 0.5").unwrap(), Value::Float(549755813888.0));
+    assert_eq!(evaluate("(-(2 ** 40) - 1) // 2").unwrap().to_string(), "-549755813889");
+    assert_eq!(run_expression("(-(2 ** 40) - 1) % 2").unwrap(), 1);
+
+    // combinations, exactly: 60 choose 30
+    let choose = "fn (fact (n) if (n <= 1 1 n * fact(n - 1)) fact(60) / (fact(30) * fact(30)))";
+    assert_eq!(evaluate(choose).unwrap().to_string(), "118264581564861424");
+}
+
+#[test]
+fn test_overflow_modes() {
+    use expression_solver::value::{OverflowMode, Value};
+    use expression_solver::vm::VmConfig;
+
+    let run_with = |source: &str, overflow: OverflowMode| {
+        let config = VmConfig {
+            overflow,
+            ..VmConfig::default()
+        };
+        run_with_config(source, config).map_err(|e| e.to_string())
+    };
+
+    let wrapping = OverflowMode::Wrapping;
+    assert_eq!(run_with("2147483647 + 1", wrapping).unwrap(), Some(Value::Int(i32::MIN)));
+    assert_eq!(run_with("0 - 2147483647 - 2", wrapping).unwrap(), Some(Value::Int(i32::MAX)));
+    assert_eq!(run_with("65536 * 65536 + 5", wrapping).unwrap(), Some(Value::Int(5)));
+
+    let saturating = OverflowMode::Saturating;
+    assert_eq!(run_with("2147483647 + 1", saturating).unwrap(), Some(Value::Int(i32::MAX)));
+    assert_eq!(run_with("0 - 3 ** 40", saturating).unwrap(), Some(Value::Int(i32::MIN + 1)));
+    assert_eq!(run_with("2 ** 10", saturating).unwrap(), Some(Value::Int(1024)));
+
+    #[cfg(not(feature = "bignum"))]
+    assert!(
+        run_with("2147483647 + 1", OverflowMode::Checked)
+            .unwrap_err()
+            .contains("Integer overflow in addition")
+    );
+
+    // no mode turns a negative exponent into a huge one
+    for mode in [OverflowMode::Checked, wrapping, saturating] {
+        let message = run_with("2 ** -1", mode).unwrap_err();
+        assert!(message.contains("Negative exponent -1"), "{:?}: {}", mode, message);
+        assert!(run_with("0 ** (0 - 3)", mode).is_err());
+        assert_eq!(run_with("2.0 ** -1", mode).unwrap(), Some(Value::Float(0.5)));
+    }
+
+    // a pragma wins over the VM's configuration
+    let counter = "#!overflow wrapping\n2147483647 + 2";
+    assert_eq!(
+        run_with(counter, OverflowMode::Saturating).unwrap(),
+        Some(Value::Int(i32::MIN + 1))
+    );
+    assert_eq!(run_expression(counter).unwrap(), i32::MIN + 1);
+    assert_eq!(
+        run_expression("#!overflow saturating\ndefine (x 2147483000 x * 2)").unwrap(),
+        i32::MAX
+    );
+}
+
+#[test]
+fn test_invalid_pragmas() {
+    use expression_solver::error::ParseErrorKind;
+
+    let pragma_error = |source: &str| {
+        let (kind, message, span) = error_parts::<ParseErrorKind>(source);
+        assert_eq!(kind, ParseErrorKind::InvalidPragma, "{}", message);
+        (message, span.unwrap())
+    };
+
+    let (message, span) = pragma_error("#!overflow sideways\n1 + 2");
+    assert!(message.contains("sideways"), "{}", message);
+    assert_eq!((span.line, span.column, span.end), (1, 1, 19));
+
+    let (message, _) = pragma_error("#!optimize\n1");
+    assert!(message.contains("Unknown pragma"), "{}", message);
+
+    // past the first token, or not at the start of a line, `#!` is a comment
+    assert_eq!(run_expression("1 #!oops").unwrap(), 1);
+    assert_eq!(run_expression("define (x 1\n#!overflow sideways\nx)").unwrap(), 1);
+    assert_eq!(run_expression("2147483647 #!overflow wrapping\n").unwrap(), i32::MAX);
+    assert!(run_expression("#!overflow wrapping\n1 #!overflow sideways").is_ok());
+}
+
+#[test]
+fn test_rationals() {
+    use expression_solver::value::Value;
+
+    let rational = |source: &str| evaluate(&format!("#!division rational\n{}", source));
+    let shown = |source: &str| rational(source).unwrap().to_string();
+
+    assert_eq!(shown("7 / 3"), "7/3");
+    assert_eq!(shown("14 / 6"), "7/3");
+    assert_eq!(shown("7 / (0 - 3)"), "-7/3");
+    assert_eq!(shown("1 / 2 + 1 / 3"), "5/6");
+    assert_eq!(shown("(2 / 3) ** 2"), "4/9");
+    assert_eq!(shown("(2 / 3) ** (0 - 2)"), "9/4");
+    assert_eq!(shown("7 / 3 % 1"), "1/3");
+    // whole results are plain integers again
+    assert_eq!(rational("1 / 3 * 3").unwrap(), Value::Int(1));
+    assert_eq!(rational("6 / 3").unwrap(), Value::Int(2));
+    assert_eq!(rational("7 / 3 // 1").unwrap(), Value::Int(2));
+    assert_eq!(rational("1 / 2 + 0.25").unwrap(), Value::Float(0.75));
+    assert_eq!(rational("1 / 3 < 1 / 2").unwrap(), Value::Bool(true));
+    assert_eq!(rational("2 / 4 == 1 / 2").unwrap(), Value::Bool(true));
+
+    assert_eq!(rational("int(7 / 3)").unwrap(), Value::Int(2));
+    assert_eq!(rational("int(0 - 7 / 3)").unwrap(), Value::Int(-2));
+    assert_eq!(rational("float(7 / 2)").unwrap(), Value::Float(3.5));
+    assert_eq!(evaluate("int(2.9) + float(1)").unwrap(), Value::Float(3.0));
+
+    // without the pragma `/` still truncates
+    assert_eq!(evaluate("7 / 3").unwrap(), Value::Int(2));
+
+    assert!(rational("1 / 0").unwrap_err().contains("Cannot Divide By Zero"));
+    assert!(
+        rational("1 / 2147483647 / 2147483647 / 2147483647")
+            .unwrap_err()
+            .contains("Rational overflow in division")
+    );
+    assert!(evaluate("int(1e20)").unwrap_err().contains("Integer overflow"));
+
+    // a whole result is never left as a fraction over 1, even past i32
+    for source in ["(0 - 2147483647 - 1) / (0 - 1)", "1 / 2 + 2147483647 + 1 / 2"] {
+        #[cfg(not(feature = "bignum"))]
+        assert!(rational(source).unwrap_err().contains("overflow"), "{}", source);
+        #[cfg(feature = "bignum")]
+        {
+            assert_eq!(shown(source), "2147483648");
+            assert_eq!(rational(&format!("({}) - 1", source)).unwrap(), Value::Int(i32::MAX));
+        }
+    }
+}
+
+#[test]
+fn test_builtin_errors() {
+    assert!(evaluate("int(1, 2)").unwrap_err().contains("`int` takes 1 argument(s)"));
+    assert!(evaluate("int + 1").unwrap_err().contains("builtin and can only be called"));
+    assert!(evaluate("fn (f (x) x float(f))").unwrap_err().contains("`float` expects a number"));
+    // a program's own binding shadows the builtin
+    assert_eq!(run_expression("fn (int (x) x + 1 int(1))").unwrap(), 2);
+}
+
+#[test]
+fn test_logical_operators() {
+    use expression_solver::value::Value;
+
+    assert!(run_bool("true").unwrap());
+    assert!(!run_bool("false").unwrap());
+    assert!(run_bool("1 < 2 and 2 < 3").unwrap());
+    assert!(!run_bool("1 < 2 && 3 < 2").unwrap());
+    assert!(run_bool("1 > 2 or 2 < 3").unwrap());
+    assert!(!run_bool("false || 0").unwrap());
+    assert!(!run_bool("not true").unwrap());
+    assert!(!run_bool("!0 == !5").unwrap());
+    assert!(run_bool("!(1 == 2)").unwrap());
+    // results are always booleans
+    assert!(run_bool("7 and 9").unwrap());
+    assert!(run_bool("0 or 2.5").unwrap());
+    // `and` binds tighter than `or`
+    assert!(run_bool("true or true and false").unwrap());
+    assert_eq!(run_expression("define (x 5 if (x > 0 && x < 10 1 2))").unwrap(), 1);
+
+    // the right side is never evaluated once the left side decides
+    assert!(!run_bool("false and 1 / 0").unwrap());
+    assert!(run_bool("true or 1 / 0").unwrap());
+    assert!(run_expression("true and 1 / 0").is_err());
+
+    // booleans are their own type, not the integers 1 and 0
+    assert_eq!(evaluate("true").unwrap().to_string(), "true");
+    assert_eq!(evaluate("3 > 2").unwrap(), Value::Bool(true));
+    assert!(run_bool("(1 < 2) == true && false < true").unwrap());
+    assert!(evaluate("true + true").unwrap_err().contains("Type mismatch in addition: bool and bool"));
+    assert!(evaluate("true < 1").unwrap_err().contains("Type mismatch in comparison"));
+
+    // `and`, `or` and `not` agree with `if` on what is true: strings, lists
+    // and functions are, whatever they hold
+    let truthy = [r#""a""#, r#""""#, "[1]", "[]", "f"];
+    for value in truthy {
+        let with_f = |source: String| format!("fn (f () 0 {})", source);
+        assert_eq!(run_expression(&with_f(format!("if ({} 1 2)", value))).unwrap(), 1);
+        assert!(!run_bool(&with_f(format!("not {}", value))).unwrap(), "not {}", value);
+        assert!(run_bool(&with_f(format!("1 and {}", value))).unwrap(), "1 and {}", value);
+        assert!(run_bool(&with_f(format!("{} or 0", value))).unwrap(), "{} or 0", value);
+        assert!(!run_bool(&with_f(format!("{} and false", value))).unwrap(), "{} and false", value);
+    }
+    assert!(!run_bool("not 0.0 and 0").unwrap());
+}
+
+#[test]
+fn test_bitwise_operators() {
+    use expression_solver::error::RuntimeErrorKind;
+
+    assert_eq!(run_expression("12 & 10").unwrap(), 8);
+    assert_eq!(run_expression("12 | 10").unwrap(), 14);
+    assert_eq!(run_expression("12 ^ 10").unwrap(), 6);
+    assert_eq!(run_expression("~0").unwrap(), -1);
+    assert_eq!(run_expression("1 << 4").unwrap(), 16);
+    assert_eq!(run_expression("1 << 31").unwrap(), i32::MIN);
+    assert_eq!(run_expression("0 - 16 >> 2").unwrap(), -4);
+    // shifts bind looser than arithmetic, bitwise operators tighter than
+    // comparisons: `|` < `^` < `&` < shifts
+    assert_eq!(run_expression("1 << 2 + 1").unwrap(), 8);
+    assert!(run_bool("6 & 3 == 2").unwrap());
+    assert_eq!(run_expression("1 | 6 & 3").unwrap(), 3);
+    assert_eq!(run_expression("3 ^ 1 | 4").unwrap(), 6);
+    // the mask of bits 4 through 7, cleared in a register value
+    assert_eq!(run_expression("define (mask ((1 << 4) - 1) << 4 255 & ~mask)").unwrap(), 15);
+    assert!(!run_bool("true && 1 & 2").unwrap());
+
+    for source in ["1 << 32", "1 >> 0 - 1"] {
+        let (kind, message, _) = error_parts::<RuntimeErrorKind>(source);
+        assert_eq!(kind, RuntimeErrorKind::ShiftOutOfRange);
+        assert!(message.contains("out of range"), "{}", message);
+    }
+    assert!(evaluate("1.5 & 1").unwrap_err().contains("Type mismatch in bitwise and"));
+    assert!(evaluate("~2.0").unwrap_err().contains("Type mismatch in bitwise not"));
+}
+
+#[test]
+fn test_integer_literals() {
+    use expression_solver::error::LexErrorKind;
+    use expression_solver::lexer::{Lexer, TokenKind};
+
+    let kinds = |source: &str| -> Vec<TokenKind> {
+        Lexer::new(source).tokenize().unwrap().into_iter().map(|t| t.kind).collect()
+    };
+    assert_eq!(kinds("0x1F"), vec![TokenKind::Number(31)]);
+    assert_eq!(kinds("0XfF"), vec![TokenKind::Number(255)]);
+    assert_eq!(kinds("0o17"), vec![TokenKind::Number(15)]);
+    assert_eq!(kinds("0b1010"), vec![TokenKind::Number(10)]);
+    assert_eq!(kinds("1_000_000"), vec![TokenKind::Number(1_000_000)]);
+    assert_eq!(kinds("0b1111_0000"), vec![TokenKind::Number(0xF0)]);
+    assert_eq!(kinds("1_000.5"), vec![TokenKind::Float(1000.5)]);
+    // radix literals are bit patterns, so the top bit may be set
+    assert_eq!(kinds("0xFFFFFFFF"), vec![TokenKind::Number(-1)]);
+    assert_eq!(kinds("0x80000000"), vec![TokenKind::Number(i32::MIN)]);
+    assert_eq!(run_expression("0xF0 | 0b1010").unwrap(), 250);
+
+    let number_error = |source: &str| {
+        let (kind, message, span) = error_parts::<LexErrorKind>(source);
+        assert_eq!(kind, LexErrorKind::InvalidNumber, "{}", message);
+        (message, span.unwrap())
+    };
+
+    let (message, span) = number_error("1 + 99999999999");
+    assert!(message.contains("99999999999 does not fit"), "{}", message);
+    assert_eq!((span.column, span.end), (5, 15));
+
+    let (message, span) = number_error("2 * 0x1_0000_0000");
+    assert!(message.contains("does not fit"), "{}", message);
+    assert_eq!((span.column, span.end), (5, 17));
+
+    let (message, span) = number_error("0b1012 + 1");
+    assert!(message.contains("Invalid digit '2' in binary literal"), "{}", message);
+    assert_eq!((span.column, span.end), (1, 6));
+
+    let (message, _) = number_error("0x + 1");
+    assert!(message.contains("Expected hexadecimal digits after 0x"), "{}", message);
+}
+
+#[test]
+fn test_comments() {
+    use expression_solver::error::{Error, LexErrorKind};
+    use expression_solver::lexer::{Lexer, TokenKind, TriviaKind};
+
+    assert_eq!(run_expression("1 + 2 # three").unwrap(), 3);
+    assert_eq!(run_expression("# leading\n1 /* inline */ + 2").unwrap(), 3);
+    assert_eq!(run_expression("7 // 2 # floor division is not a comment").unwrap(), 3);
+    assert_eq!(run_expression("1 /* outer /* nested */ still outer */ + 1").unwrap(), 2);
+    assert_eq!(run_expression("#!overflow wrapping\n# comment after a pragma\n2147483647 + 1").unwrap(), i32::MIN);
+
+    let mut lexer = Lexer::new("x # note\n/* a\n block */ y");
+    let kinds: Vec<TokenKind> = lexer.tokenize().unwrap().into_iter().map(|t| t.kind).collect();
+    assert_eq!(kinds, vec![TokenKind::Ident("x".into()), TokenKind::Ident("y".into())]);
+    let trivia = lexer.trivia();
+    assert_eq!(trivia.len(), 2);
+    assert_eq!(trivia[0].kind, TriviaKind::LineComment);
+    assert_eq!(trivia[0].text, "# note");
+    assert_eq!((trivia[0].span.line, trivia[0].span.column), (1, 3));
+    assert_eq!(trivia[1].kind, TriviaKind::BlockComment);
+    assert_eq!(trivia[1].text, "/* a\n block */");
+    assert_eq!(trivia[1].span.line, 2);
+
+    match Lexer::new("1 + /* open /* */").tokenize().unwrap_err() {
+        Error::Lex {
+            kind: LexErrorKind::UnterminatedComment,
+            span,
+            ..
+        } => assert_eq!(span.unwrap().column, 5),
+        other => panic!("expected an unterminated comment, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_strings() {
+    use expression_solver::lexer::{Lexer, TokenKind};
+    use expression_solver::value::Value;
+
+    let kinds = |source: &str| -> Vec<TokenKind> {
+        Lexer::new(source).tokenize().unwrap().into_iter().map(|t| t.kind).collect()
+    };
+    assert_eq!(kinds(r#""a\tb\n\"c\" \\""#), vec![TokenKind::Str("a\tb\n\"c\" \\".into())]);
+    assert_eq!(kinds(r##""# not a comment""##), vec![TokenKind::Str("# not a comment".into())]);
+
+    let shown = |source: &str| evaluate(source).unwrap().to_string();
+    assert_eq!(shown(r#""hello""#), "hello");
+    assert_eq!(shown(r#""total: " + str(6 * 7)"#), "total: 42");
+    assert_eq!(shown(r#"str(2.5) + "/" + str(1 < 2)"#), "2.5/true");
+    assert_eq!(shown(r#"substr("expression", 2, 5)"#), "press");
+    assert_eq!(shown(r#"define (s "héllo" substr(s, 1, len(s) - 1))"#), "éllo");
+    assert_eq!(evaluate(r#"len("héllo")"#).unwrap(), Value::Int(5));
+    assert_eq!(evaluate(r#"len("")"#).unwrap(), Value::Int(0));
+
+    assert!(run_bool(r#""abc" == "abc""#).unwrap());
+    assert!(run_bool(r#""abc" < "abd""#).unwrap());
+    assert_eq!(run_expression(r#"compare("pear", "apple")"#).unwrap(), 1);
+    assert_eq!(run_expression(r#"compare("a", "a")"#).unwrap(), 0);
+    assert_eq!(run_expression("compare(1, 2.5)").unwrap(), -1);
+}
+
+#[test]
+fn test_string_errors() {
+    use expression_solver::error::{LexErrorKind, RuntimeErrorKind};
+
+    let (kind, _, span) = error_parts::<LexErrorKind>(r#"1 + "abc"#);
+    assert_eq!(kind, LexErrorKind::UnterminatedString);
+    assert_eq!(span.unwrap().column, 5);
+    let (kind, message, span) = error_parts::<LexErrorKind>(r#""a\qb""#);
+    assert_eq!(kind, LexErrorKind::InvalidEscape);
+    assert!(message.contains(r"'\q'"), "{}", message);
+    assert_eq!(span.unwrap().column, 3);
+    let (kind, message, _) = error_parts::<RuntimeErrorKind>(r#"substr("abc", 2, 5)"#);
+    assert_eq!(kind, RuntimeErrorKind::IndexOutOfBounds);
+    assert!(message.contains("length 3"), "{}", message);
+
+    assert!(evaluate(r#""n = " + 1"#).unwrap_err().contains("Type mismatch in addition: string and int"));
+    assert!(evaluate(r#""a" * 2"#).unwrap_err().contains("Type mismatch"));
+    assert!(evaluate(r#""a" < 1"#).unwrap_err().contains("Type mismatch in comparison"));
+    assert!(evaluate("len(5)").unwrap_err().contains("`len` expects a string or a list, got int"));
+    assert!(evaluate(r#"define (s "f" s(1))"#).unwrap_err().contains("cannot call string"));
+}
+
+#[test]
+fn test_lists() {
+    use expression_solver::value::Value;
+
+    let shown = |source: &str| evaluate(source).unwrap().to_string();
+    assert_eq!(shown("[1, 2 + 3, 4.5]"), "[1, 5, 4.5]");
+    assert_eq!(shown("[]"), "[]");
+    assert_eq!(shown(r#"["a, b", [1], "c"]"#), r#"["a, b", [1], "c"]"#);
+    assert_eq!(shown("push([1, 2], 3)"), "[1, 2, 3]");
+    assert_eq!(shown("slice([1, 2, 3, 4], 1, 3)"), "[2, 3]");
+    assert_eq!(shown("slice([1, 2], 2, 2)"), "[]");
+    assert_eq!(shown(r#"define (s "list" s[2])"#), "s");
+    assert_eq!(evaluate("define (xs [10, 20, 30] xs[1])").unwrap(), Value::Int(20));
+    assert_eq!(evaluate("define (m [[1, 2], [3, 4]] m[1][0])").unwrap(), Value::Int(3));
+    assert_eq!(evaluate("len([1, 2, 3])").unwrap(), Value::Int(3));
+    // push builds a new list; the original is unchanged
+    assert_eq!(evaluate("define (xs [1] define (ys push(xs, 2) len(xs) * 10 + len(ys)))").unwrap(), Value::Int(12));
+    assert!(run_bool("[1, 2] == [1, 2] && [1, 2] < [1, 3] && [1] < [1, 0]").unwrap());
+    // a space before '[' starts a new list rather than indexing
+    assert_eq!(shown("if (1 [1] [2])"), "[1]");
+    assert_eq!(shown("if (1 [1][2])"), "[1]");
+
+    let mean = "fn (sum (xs i) if (i == len(xs) 0 xs[i] + sum(xs, i + 1)) \
+                define (xs [2.0, 4, 9] sum(xs, 0) / len(xs)))";
+    assert_eq!(evaluate(mean).unwrap(), Value::Float(5.0));
+    let dot = "fn (dot (xs ys i) if (i == len(xs) 0 xs[i] * ys[i] + dot(xs, ys, i + 1)) \
+               dot([1, 2, 3], [4, 5, 6], 0))";
+    assert_eq!(run_expression(dot).unwrap(), 32);
+
+    // storing an element updates the variable, not other copies of the list
+    assert_eq!(shown("define (xs [1, 2, 3] set (xs[1] 20))"), "[1, 20, 3]");
+    assert_eq!(shown("define (xs [1, 2] define (ys xs do (set (xs[0] 9) [xs, ys])))"), "[[9, 2], [1, 2]]");
+    let squares = "define (xs [0, 0, 0, 0] define (i 0 do (\
+                   while (i < len(xs) do (set (xs[i] i * i) set (i i + 1))) \
+                   xs)))";
+    assert_eq!(shown(squares), "[0, 1, 4, 9]");
+}
+
+#[test]
+fn test_list_errors() {
+    use expression_solver::error::{ParseErrorKind, RuntimeErrorKind};
+
+    let bounds_error = |source: &str| {
+        let (kind, message, span) = error_parts::<RuntimeErrorKind>(source);
+        assert_eq!(kind, RuntimeErrorKind::IndexOutOfBounds, "{}", message);
+        (message, span.unwrap())
+    };
+    let (message, span) = bounds_error("define (xs [1, 2, 3] xs[3])");
+    assert!(message.contains("Index 3 is out of bounds for a list of length 3"), "{}", message);
+    assert_eq!((span.column, span.start, span.end), (22, 21, 26));
+    let (message, _) = bounds_error("define (xs [1] xs[0 - 1])");
+    assert!(message.contains("Index -1"), "{}", message);
+    let (message, _) = bounds_error("slice([1, 2], 1, 3)");
+    assert!(message.contains("Slice 1..3"), "{}", message);
+
+    assert!(evaluate("define (xs [1] xs[1.0])").unwrap_err().contains("Type mismatch in indexing: list and float"));
+    assert!(evaluate("define (n 5 n[0])").unwrap_err().contains("Type mismatch in indexing: int and int"));
+    assert!(evaluate("[1] + [2]").unwrap_err().contains("Type mismatch in addition"));
+    assert!(evaluate("push(1, 2)").unwrap_err().contains("`push` expects a list and a value"));
+    let (message, _) = bounds_error("define (xs [1] set (xs[1] 2))");
+    assert!(message.contains("Index 1 is out of bounds for a list of length 1"), "{}", message);
+    assert!(
+        evaluate(r#"define (s "ab" set (s[0] "c"))"#)
+            .unwrap_err()
+            .contains("Type mismatch in element assignment: string and int")
+    );
+
+    let (kind, _, _) = error_parts::<ParseErrorKind>("[1, 2");
+    assert!(matches!(kind, ParseErrorKind::Unclosed { close: ']', .. }), "{:?}", kind);
+}
+
+#[test]
+fn test_garbage_collection() {
+    use expression_solver::value::Value;
+    use expression_solver::vm::{VmConfig, VM};
+
+    let run_with = |source: &str, gc_threshold: usize| {
+        let program = compile_source(source);
+        let mut log_file = File::create("/tmp/test_gc_log.log").unwrap();
+        let mut vm = VM::with_config(VmConfig {
+            gc_threshold,
+            ..VmConfig::default()
+        });
+        let result = vm.run(&program, &mut log_file).unwrap();
+        (result, vm.gc_stats())
+    };
+
+    // every iteration makes a closure that is garbage by the next one
+    let churn = "define (i 0 define (last while (i < 2000 \
+                 define (j i fn (g () j define (_ set (i (i + 1)) g)))) i))";
+    let (result, stats) = run_with(churn, 1024);
+    assert_eq!(result, Some(Value::Int(2000)));
+    assert!(stats.collections > 0, "{:?}", stats);
+    assert!(stats.objects_freed > 1900, "{:?}", stats);
+    assert!(stats.bytes_freed > 0);
+    assert!(stats.bytes_live <= 2048, "the heap kept growing: {:?}", stats);
+
+    // closures that are still reachable survive, including through lists
+    let kept = "fn (adder (n) fn (add (x) x + n add) \
+                define (fs [adder(1), adder(2)] \
+                define (i 0 \
+                define (w while (i < 500 define (junk adder(i) set (i (i + 1)))) \
+                define (f fs[1] f(40))))))";
+    let (result, stats) = run_with(kept, 256);
+    assert_eq!(result, Some(Value::Int(42)));
+    assert!(stats.collections > 0, "{:?}", stats);
+
+    // below the threshold nothing is collected
+    let (_, stats) = run_with(churn, usize::MAX);
+    assert_eq!(stats.collections, 0);
+    assert_eq!(stats.objects_freed, 0);
+
+    // a VM can run one program after another, even after a failed run, and
+    // a pragma only applies to the program that has it
+    let mut vm = VM::new();
+    let mut log_file = File::create("/tmp/test_gc_log.log").unwrap();
+    let mut run = |source: &str| vm.run(&compile_source(source), &mut log_file);
+    assert_eq!(run("define (x 2 x * 21)").unwrap(), Some(Value::Int(42)));
+    assert!(run("1 / 0").is_err());
+    assert_eq!(run("#!overflow wrapping\n2147483647 + 1").unwrap(), Some(Value::Int(i32::MIN)));
+    assert_eq!(run(churn).unwrap(), Some(Value::Int(2000)));
+    #[cfg(not(feature = "bignum"))]
+    assert!(run("2147483647 + 1").is_err());
+}
+
+#[test]
+fn test_assignment() {
+    use expression_solver::error::CompileErrorKind;
+
+    assert_eq!(run_expression("define (x 1 set (x x + 41))").unwrap(), 42);
+    assert_eq!(run_expression("define (x 1 define (_ set (x 5) x * 2))").unwrap(), 10);
+    // `define` always makes a new variable, which only lives for its body
+    assert_eq!(run_expression("define (x 1 (define (x 2 x)) + x)").unwrap(), 3);
+    assert_eq!(run_expression("define (x 1 (define (y 2 set (x y))) + x)").unwrap(), 4);
+    // an inner define shadows, so `set` reaches the inner variable only
+    assert_eq!(run_expression("define (x 1 define (_ define (x 2 set (x 10)) x))").unwrap(), 1);
+    // a function's own parameters and variables can be assigned
+    assert_eq!(run_expression("fn (f (n) define (_ set (n n * 3) n + 1) f(2))").unwrap(), 7);
+    let countdown = "define (n 10 define (steps 0 define (_ while (n > 0 \
+                     define (_ set (n n // 2) set (steps steps + 1))) steps)))";
+    assert_eq!(run_expression(countdown).unwrap(), 4);
+
+    let assign_error = |source: &str| {
+        let (kind, message, span) = error_parts::<CompileErrorKind>(source);
+        let span = span.unwrap();
+        (kind, message, source[span.start..span.end].to_string())
+    };
+
+    let (kind, message, text) = assign_error("define (x 1 set (y 2))");
+    assert_eq!(kind, CompileErrorKind::UndefinedVariable);
+    assert!(message.contains("undeclared variable `y`"), "{}", message);
+    assert_eq!(text, "y");
+
+    let (kind, message, _) = assign_error("fn (f () 1 set (f 2))");
+    assert_eq!(kind, CompileErrorKind::InvalidAssignment);
+    assert!(message.contains("function `f`"), "{}", message);
+
+    let (kind, message, _) = assign_error("define (x 1 fn (f () set (x 2) f()))");
+    assert_eq!(kind, CompileErrorKind::InvalidAssignment);
+    assert!(message.contains("captures"), "{}", message);
+
+    // a closure holds a copy of `n` while a direct call reads the variable, so
+    // `n` cannot be assigned anywhere once a function captures it
+    let (kind, message, text) =
+        assign_error("define (n 1 fn (f () n define (g f define (_ set (n 2) f() * 10 + g()))))");
+    assert_eq!(kind, CompileErrorKind::InvalidAssignment);
+    assert!(message.contains("a function captures"), "{}", message);
+    assert_eq!(text, "n");
+    let (kind, _, _) = assign_error("fn (adder (n) define (_ set (n n + 1) fn (add (x) x + n add)) adder(1))");
+    assert_eq!(kind, CompileErrorKind::InvalidAssignment);
+
+    // the assigned name goes out of scope with its define
+    let (kind, _, _) = assign_error("(define (x 1 x)) + (set (x 2))");
+    assert_eq!(kind, CompileErrorKind::UndefinedVariable);
+}
+
+#[test]
+fn test_blocks() {
+    use expression_solver::compiler::compile;
+    use expression_solver::error::{CompileErrorKind, Error, ParseErrorKind};
+    use expression_solver::lexer::Span;
+    use expression_solver::parser::{Expr, ExprKind};
+    use expression_solver::resolver::resolve;
+    use expression_solver::vm::Instruction;
+
+    assert_eq!(run_expression("do (1 2 3)").unwrap(), 3);
+    assert_eq!(run_expression("do (7)").unwrap(), 7);
+    assert_eq!(run_expression("define (x 1 do (set (x x + 1) set (x x * 10) x + 2))").unwrap(), 22);
+    assert_eq!(run_expression("1 + (do (2 3)) * 2").unwrap(), 7);
+    // a define inside a block only reaches the end of its own body
+    assert_eq!(run_expression("define (x 1 do (define (x 5 x) x))").unwrap(), 1);
+
+    let summed = "define (i 0 define (total 0 do (\
+                  while (i < 5 do (set (total total + i) set (i i + 1))) \
+                  total)))";
+    assert_eq!(run_expression(summed).unwrap(), 10);
+
+    // each value but the last is popped, so nothing piles up on the stack
+    let program = compile_source("do (1 2 3)");
+    let pops = program
+        .spans
+        .iter()
+        .filter(|&&(pos, _)| program.code[pos] == Instruction::POP as i32)
+        .count();
+    assert_eq!(pops, 2);
+    let long = format!("do ({})", "1 ".repeat(1000));
+    assert_eq!(run_expression(&long).unwrap(), 1);
+
+    // the last expression is in tail position
+    let countdown = "fn (down (n) do (n + 0 if (n == 0 0 down(n - 1))) down(100000))";
+    assert_eq!(run_expression(countdown).unwrap(), 0);
+
+    let (kind, _, _) = error_parts::<ParseErrorKind>("do ()");
+    assert_eq!(kind, ParseErrorKind::UnexpectedToken);
+
+    // an empty block built by hand is an error, not a panic
+    let resolved = resolve(Expr::new(ExprKind::Block(Vec::new()), Span::default())).unwrap();
+    match compile(&resolved) {
+        Err(Error::Compile { kind, .. }) => assert_eq!(kind, CompileErrorKind::InvalidSyntax),
+        other => panic!("expected a compile error, got {:?}", other.map(|_| ())),
+    }
+}